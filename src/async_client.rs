@@ -0,0 +1,266 @@
+//! An async client for sbt's server, built on tokio, that can have many requests in flight at
+//! once: e.g. an editor asking for completions while a compile runs.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), sbtl::ClientError> {
+//! let client = sbtl::async_client::AsyncSbtClient::connect(std::path::Path::new(".")).await?;
+//! client.initialize().await?;
+//! let compile = client.start_exec("compile").await?;
+//! let completions = client.completion("testO").await?; // answered while compiling
+//! println!("{:?}, {:?}", completions, compile.wait().await?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! It's behind the `async` feature.
+
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf, };
+use std::sync::{ Arc, Mutex, };
+use std::sync::atomic::{ AtomicI64, Ordering, };
+
+use serde_json::Value;
+use tokio::io::{ AsyncRead, AsyncWrite, BufReader, };
+use tokio::net::{ TcpStream, UnixStream, };
+use tokio::sync::{ mpsc, oneshot, };
+use tokio::task::JoinHandle;
+
+use crate::client;
+use crate::client::{ JsonRpcId, ServerFeatures, ServerUri, };
+use crate::codec::nonblocking::{ AsyncMessageReader, AsyncMessageWriter, };
+use crate::sbt_client;
+use crate::sbt_client::{ ClientError, ExecResult, Notification, Setting, };
+
+type Reader = AsyncMessageReader<BufReader<Box<dyn AsyncRead + Unpin + Send>>>;
+type Writer = Arc<tokio::sync::Mutex<AsyncMessageWriter<Box<dyn AsyncWrite + Unpin + Send>>>>;
+type Response = Result<Value, ClientError>;
+
+/// What the client and the task reading the server's messages share.
+#[derive(Default)]
+struct Routes {
+    /// The requests awaiting responses, by id, or `None` once the server's gone.
+        pending: Mutex<Option<HashMap<JsonRpcId, oneshot::Sender<Response>>>>,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Notification>>>,
+}
+
+pub struct AsyncSbtClient {
+      writer: Writer,
+      routes: Arc<Routes>,
+     next_id: AtomicI64,
+      reader: JoinHandle<()>,
+       token: Option<String>,
+        root: PathBuf,
+    features: Mutex<ServerFeatures>,
+}
+
+/// A request in flight, see `AsyncSbtClient::start_exec`.
+pub struct Pending<T> {
+       id: JsonRpcId,
+       rx: oneshot::Receiver<Response>,
+    parse: fn(Value) -> T,
+}
+
+impl<T> Pending<T> {
+    /// The id of the request, to `cancel` it with.
+    pub fn id(&self) -> JsonRpcId { self.id }
+
+    /// Waits for the response.
+    pub async fn wait(self) -> Result<T, ClientError> {
+        match self.rx.await {
+            Ok(response) => response.map(self.parse),
+            Err(_)       => Err(ClientError::Disconnected),
+        }
+    }
+}
+
+impl AsyncSbtClient {
+    /// Connects to the server running in the project at `root`, as advertised by its port file.
+    pub async fn connect(root: &Path) -> Result<AsyncSbtClient, ClientError> {
+        let (uri, token) = sbt_client::read_port_file(root)?;
+        AsyncSbtClient::connect_to(&uri, token, root).await
+    }
+
+    /// Connects to the server at the given URI, e.g. `local:///path/to/sock`, for the project at `root`.
+    pub async fn connect_to(uri: &str, token: Option<String>, root: &Path) -> Result<AsyncSbtClient, ClientError> {
+        let (read, write): (Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncWrite + Unpin + Send>) =
+            match ServerUri::parse(uri).map_err(ClientError::Protocol)? {
+                ServerUri::Local(path) => {
+                    let (read, write) = UnixStream::connect(path).await?.into_split();
+                    (Box::new(read), Box::new(write))
+                },
+                ServerUri::Tcp(addr)   => {
+                    let (read, write) = TcpStream::connect(addr).await?.into_split();
+                    (Box::new(read), Box::new(write))
+                },
+            };
+        Ok(AsyncSbtClient::new(read, write, token, root))
+    }
+
+    /// Starts reading the server's messages, which must be from within a tokio runtime.
+    fn new(read: Box<dyn AsyncRead + Unpin + Send>, write: Box<dyn AsyncWrite + Unpin + Send>, token: Option<String>, root: &Path) -> AsyncSbtClient {
+        let writer = Arc::new(tokio::sync::Mutex::new(AsyncMessageWriter::new(write)));
+        let routes = Arc::new(Routes { pending: Mutex::new(Some(HashMap::new())), ..Routes::default() });
+        let reader = AsyncMessageReader::new(BufReader::new(read));
+        let reader = tokio::spawn(route_messages(reader, writer.clone(), routes.clone()));
+        AsyncSbtClient { writer, routes, next_id: AtomicI64::new(1), reader, token, root: root.to_path_buf(), features: Mutex::default() }
+    }
+
+    /// Performs the `initialize` handshake and `initialized` notification, returning the server's capabilities.
+    pub async fn initialize(&self) -> Result<Value, ClientError> {
+        let params = sbt_client::initialize_params(self.token.as_deref(), &self.root);
+        let result = self.request("initialize", &params, |result| result).await?.wait().await?;
+        self.write(&client::notification("initialized", &json!({}))).await?;
+        let capabilities = result["capabilities"].clone();
+        let sbt_version = self.setting_query("sbtVersion").await;
+        *self.features.lock().unwrap() = sbt_client::features(&capabilities, sbt_version);
+        Ok(capabilities)
+    }
+
+    /// A stream of all the notifications the server sends from now on, which ends when the server's gone.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Notification> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.routes.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Runs a command, e.g. `compile` or `testOnly foo.BarSpec`, waiting for it to finish.
+    pub async fn exec(&self, command_line: &str) -> Result<ExecResult, ClientError> {
+        self.start_exec(command_line).await?.wait().await
+    }
+
+    /// Starts running a command, returning it in flight, to `wait` on or `cancel`.
+    pub async fn start_exec(&self, command_line: &str) -> Result<Pending<ExecResult>, ClientError> {
+        self.request("sbt/exec", &sbt_client::exec_params(command_line), ExecResult::from_result).await
+    }
+
+    /// Completions for a partial command line (sbt 1.4+).
+    pub async fn completion(&self, query: &str) -> Result<Vec<String>, ClientError> {
+        if !self.features.lock().unwrap().completion { return Err(ClientError::Unsupported("sbt/completion")) }
+        self.request("sbt/completion", &json!({"query": query}), sbt_client::completion_items).await?.wait().await
+    }
+
+    /// The value of a setting, e.g. `scalaVersion` or `root/name`.
+    pub async fn setting_query(&self, setting: &str) -> Result<Setting, ClientError> {
+        self.request("sbt/setting", &sbt_client::setting_params(setting), Setting::from_result).await?.wait().await
+    }
+
+    /// Asks the server to cancel a request (sbt 1.4+); the request then ends with an error that
+    /// `is_cancelled`.
+    pub async fn cancel(&self, id: JsonRpcId) -> Result<(), ClientError> {
+        let cancel_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.write(&sbt_client::cancel_request(cancel_id, id)).await
+    }
+
+    async fn request<T>(&self, method: &str, params: &Value, parse: fn(Value) -> T) -> Result<Pending<T>, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        // registered before it's sent, so the response can't beat us to it
+        match self.routes.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None          => return Err(ClientError::Disconnected),
+        };
+        if let Err(e) = self.write(&client::request(id, method, params)).await {
+            if let Some(pending) = self.routes.pending.lock().unwrap().as_mut() { pending.remove(&id); }
+            return Err(e)
+        }
+        Ok(Pending { id, rx, parse })
+    }
+
+    async fn write(&self, msg: &Value) -> Result<(), ClientError> {
+        Ok(self.writer.lock().await.write_message(msg).await?)
+    }
+}
+
+impl Drop for AsyncSbtClient {
+    fn drop(&mut self) { self.reader.abort() }
+}
+
+/// Reads the server's messages until it's gone, routing responses to their requests and
+/// notifications to the subscribers, and turning down any requests from the server.
+async fn route_messages(mut reader: Reader, writer: Writer, routes: Arc<Routes>) {
+    let end = loop {
+        let msg = match reader.read_message().await {
+            Ok(Some(msg)) => msg,
+            Ok(None)      => break None,
+            Err(e)        => break Some(e.to_string()),
+        };
+        match (msg.get("id"), msg["method"].as_str()) {
+            (Some(id), None)         => {
+                let tx = sbt_client::response_id(id).and_then(|id| routes.pending.lock().unwrap().as_mut()?.remove(&id));
+                if let Some(tx) = tx { let _ = tx.send(sbt_client::response_result(&msg)); }
+            },
+            (Some(id), Some(method)) => {
+                let _ = writer.lock().await.write_message(&sbt_client::method_not_found(id, method)).await;
+            },
+            (None, Some(method))     => {
+                let notification = Notification { method: method.to_owned(), params: msg["params"].clone() };
+                routes.subscribers.lock().unwrap().retain(|tx| tx.send(notification.clone()).is_ok());
+            },
+            (None, None)             => (),
+        }
+    };
+    let pending = routes.pending.lock().unwrap().take().unwrap_or_default();
+    for (_, tx) in pending {
+        let _ = tx.send(Err(end.clone().map_or(ClientError::Disconnected, ClientError::Protocol)));
+    }
+    routes.subscribers.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ServerReader = AsyncMessageReader<BufReader<tokio::net::unix::OwnedReadHalf>>;
+    type ServerWriter = AsyncMessageWriter<tokio::net::unix::OwnedWriteHalf>;
+
+    fn client_and_server() -> (AsyncSbtClient, ServerReader, ServerWriter) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (read, write) = ours.into_split();
+        let client = AsyncSbtClient::new(Box::new(read), Box::new(write), None, Path::new("/p"));
+        let (read, write) = theirs.into_split();
+        (client, AsyncMessageReader::new(BufReader::new(read)), AsyncMessageWriter::new(write))
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests() {
+        let (client, mut reader, mut writer) = client_and_server();
+        let server = tokio::spawn(async move {
+            let exec = reader.read_message().await.unwrap().unwrap();
+            let completion = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(exec["method"], "sbt/exec");
+            assert_eq!(completion["method"], "sbt/completion");
+            // the completion's answered mid compile
+            writer.write_message(&json!({"jsonrpc": "2.0", "id": completion["id"], "result": {"items": ["testOnly"]}})).await.unwrap();
+            writer.write_message(&json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"message": "compiling"}})).await.unwrap();
+            writer.write_message(&json!({"jsonrpc": "2.0", "id": 9, "method": "window/workDoneProgress/create"})).await.unwrap();
+            let turned_down = reader.read_message().await.unwrap().unwrap();
+            writer.write_message(&json!({"jsonrpc": "2.0", "id": exec["id"].to_string(), "result": {"status": "Done", "exitCode": 0}})).await.unwrap();
+            turned_down
+        });
+        let mut notifications = client.subscribe();
+        *client.features.lock().unwrap() = ServerFeatures::new(&json!({}), "1.4.0");
+
+        let compile = client.start_exec("compile").await.unwrap();
+        assert_eq!(client.completion("testO").await.unwrap(), vec!["testOnly"]);
+        assert_eq!(notifications.recv().await.unwrap().params["message"], "compiling");
+        assert_eq!(compile.wait().await.unwrap(), ExecResult { status: "Done".to_owned(), exit_code: Some(0) });
+        let turned_down = server.await.unwrap();
+        assert_eq!(turned_down["id"], 9);
+        assert_eq!(turned_down["error"]["code"], client::METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn server_gone() {
+        let (client, mut reader, writer) = client_and_server();
+        let mut notifications = client.subscribe();
+        let compile = client.start_exec("compile").await.unwrap();
+        let test = client.start_exec("test").await.unwrap();
+        assert_eq!(reader.read_message().await.unwrap().unwrap()["params"]["commandLine"], "compile");
+        drop((reader, writer));
+
+        assert!(matches!(compile.wait().await, Err(ClientError::Disconnected)));
+        assert!(matches!(test.wait().await, Err(ClientError::Disconnected)));
+        assert!(notifications.recv().await.is_none());
+        assert!(client.setting_query("name").await.is_err());
+    }
+}
@@ -0,0 +1,373 @@
+//! `sbtl bsp`, a Build Server Protocol client for sbt 1.4+, which speaks BSP on the same socket
+//! as its own protocol, or through the `sbt -bsp` command its `.bsp/sbt.json` connection file gives.
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{ BufReader, IsTerminal, };
+use std::path::PathBuf;
+use std::process::{ Command, Stdio, exit, };
+
+use serde_json::Value;
+
+use crate::client;
+use crate::client::{ ServerFeatures, Session, };
+use crate::diagnostics::{ FileDiagnostics, Renderer, };
+use crate::log;
+use crate::log::Logger;
+use crate::project;
+use crate::test_report::TestReport;
+
+/// The version of BSP we speak.
+const BSP_VERSION: &str = "2.1.0";
+
+/// `StatusCode` in the results of `buildTarget/compile`, `buildTarget/test` and `buildTarget/run`.
+const STATUS_OK: i64 = 1;
+const STATUS_CANCELLED: i64 = 3;
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Targets,
+    Compile(Vec<String>),
+    Test(Vec<String>),
+    Run(Option<String>, Vec<String>),
+}
+
+impl Action {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Action {
+        match args.next().as_deref() {
+            Some("targets") => Action::Targets,
+            Some("compile") => Action::Compile(args.collect()),
+            Some("test")    => Action::Test(args.collect()),
+            Some("run")     => {
+                let mut args = args.peekable();
+                let target = if args.peek().is_some_and(|arg| arg != "--") { args.next() } else { None };
+                // everything after the `--` is the program's, as it is, even another `--`
+                if args.peek().is_some_and(|arg| arg == "--") { args.next(); }
+                Action::Run(target, args.collect())
+            },
+            _               => { die!("usage: sbtl bsp targets | compile [target...] | test [--junit-xml <dir>] [target...] | run [target] [-- args...]"); },
+        }
+    }
+}
+
+/// A build target, as listed by `workspace/buildTargets`.
+#[derive(Debug, PartialEq)]
+struct BuildTarget {
+            uri: String,
+           name: String,
+    can_compile: bool,
+       can_test: bool,
+        can_run: bool,
+}
+
+impl BuildTarget {
+    fn from_json(json: &Value) -> Option<BuildTarget> {
+        let uri = json["id"]["uri"].as_str()?.to_owned();
+        let capability = |name: &str| json["capabilities"][name].as_bool().unwrap_or(false);
+        Some(BuildTarget {
+                   name: json["displayName"].as_str().unwrap_or(&uri).to_owned(),
+            can_compile: capability("canCompile"),
+               can_test: capability("canTest"),
+                can_run: capability("canRun"),
+                    uri,
+        })
+    }
+
+    fn id(&self) -> Value { json!({"uri": self.uri}) }
+}
+
+/// Picks the targets with the given names (or URIs), or else all the ones that are capable.
+fn select<'a>(targets: &'a [BuildTarget], names: &[String], capable: fn(&BuildTarget) -> bool) -> Result<Vec<&'a BuildTarget>, String> {
+    if names.is_empty() {
+        return Ok(targets.iter().filter(|t| capable(t)).collect())
+    }
+    names.iter().map(|name| {
+        match targets.iter().find(|t| &t.name == name || &t.uri == name) {
+            Some(target) if capable(target) => Ok(target),
+            Some(_)                         => Err(format!("build target {} can't do that", name)),
+            None                            => Err(format!("no build target {}, expected one of: {}", name,
+                targets.iter().map(|t| t.name.as_str()).collect::<Vec<_>>().join(", "))),
+        }
+    }).collect()
+}
+
+/// Shows `build/taskProgress` as a status line that's redrawn in place, when there's a terminal to draw it on.
+struct Progress {
+    enabled: bool,
+      shown: bool,
+}
+
+impl Progress {
+    fn show(&mut self, params: &Value) {
+        if !self.enabled { return }
+        print!("\r\x1b[K{}", format_progress(params));
+        let _ = io::stdout().flush();
+        self.shown = true;
+    }
+
+    fn clear(&mut self) {
+        if self.shown {
+            print!("\r\x1b[K");
+            self.shown = false;
+        }
+    }
+}
+
+/// E.g. `Compiling root (3/10 files)`.
+fn format_progress(params: &Value) -> String {
+    let message = params["message"].as_str().unwrap_or("");
+    match (params["progress"].as_i64(), params["total"].as_i64()) {
+        (Some(progress), Some(total)) => {
+            let unit = params["unit"].as_str().map(|unit| format!(" {}", unit)).unwrap_or_default();
+            format!("{} ({}/{}{})", message, progress, total, unit).trim_start().to_owned()
+        },
+        _                             => message.to_owned(),
+    }
+}
+
+/// Where what the server tells us ends up, as in `client`.
+struct Output {
+      logger: Logger,
+    renderer: Renderer,
+    progress: Progress,
+       tests: TestReport,
+}
+
+impl Output {
+    fn on_notification(&mut self, method: &str, params: Value) {
+        if method != "build/taskProgress" { self.progress.clear() }
+        self.tests.on_bsp(method, &params);
+        match method {
+            "build/logMessage" | "build/showMessage" => {
+                let message = params["message"].as_str().unwrap_or("");
+                self.logger.log(log::Level::from_lsp(params["type"].as_i64().unwrap_or(3), message), message);
+            },
+            "build/publishDiagnostics"               => {
+                let params = json!({"uri": params["textDocument"]["uri"], "diagnostics": params["diagnostics"]});
+                if let Some(file) = FileDiagnostics::from_params(&params) {
+                    print!("{}", self.renderer.render(&file));
+                }
+            },
+            "build/taskStart"                        => if let Some(msg) = params["message"].as_str() { self.logger.log(log::Level::Info, msg) },
+            "build/taskFinish"                       => if let Some(msg) = params["message"].as_str() { self.logger.log(log::Level::Debug, msg) },
+            "build/taskProgress"                     => self.progress.show(&params),
+            "run/printStdout"                        => { print!("{}", params["message"].as_str().unwrap_or("")); let _ = io::stdout().flush(); },
+            "run/printStderr"                        => eprint!("{}", params["message"].as_str().unwrap_or("")),
+            _                                        => (),
+        }
+    }
+}
+
+pub fn run<I: Iterator<Item = String>>(args: I, log_level: log::Level, colors: bool) {
+    let mut args: Vec<String> = args.collect();
+    let junit_xml = take_option(&mut args, "--junit-xml").map(PathBuf::from);
+    let action = Action::parse(args.into_iter());
+    let colors = colors && io::stdout().is_terminal();
+    let mut output = Output {
+          logger: Logger::new(colors, log_level),
+        renderer: Renderer::new(colors, env::current_dir().unwrap_or_default()),
+        progress: Progress { enabled: colors, shown: false },
+           tests: TestReport::default(),
+    };
+
+    let code = match client::find_server() {
+        Some(server) => {
+            let uri = server.uri.clone();
+            let probe = Session::connect(server.stream, server.token);
+            require_bsp(probe.features(), probe.sbt_version());
+            probe.close();
+            let stream = client::Connection::connect(&uri).unwrap_or_else(|e| { die!("failed to connect to the sbt server: {}", e); });
+            let mut session = Session::open(stream);
+            let code = drive(&mut session, &action, &mut output);
+            session.close();
+            code
+        },
+        None         => {
+            // no server running, so let sbt start one, as IDEs do
+            let mut child = spawn_from_connection_file();
+            let reader = BufReader::new(child.stdout.take().unwrap());
+            let mut session = Session::new(reader, child.stdin.take().unwrap());
+            let code = drive(&mut session, &action, &mut output);
+            let id = session.send_request("build/shutdown", &Value::Null);
+            let _ = session.await_response(id);
+            session.send_notification("build/exit", &Value::Null);
+            drop(session); // closing its stdin
+            let _ = child.wait();
+            code
+        },
+    };
+
+    output.progress.clear();
+    if let Some(summary) = output.renderer.summary() {
+        println!("{}", summary);
+    }
+    if !output.tests.is_empty() {
+        print!("{}", output.tests.summary(colors));
+    }
+    if let Some(dir) = junit_xml {
+        if let Err(e) = output.tests.write_junit_xml(&dir) {
+            eprintln!("[error] failed to write the JUnit XML reports to {}: {}", dir.display(), e);
+        }
+    }
+    exit(code)
+}
+
+/// Refuses to speak BSP to a server that doesn't support it, rather than leave it puzzling over
+/// `build/initialize`.
+fn require_bsp(features: &ServerFeatures, sbt_version: Option<&str>) {
+    if !features.bsp {
+        eprintln!("[error] this sbt server (sbt {}) doesn't support BSP, which needs sbt 1.4 or later", sbt_version.unwrap_or("unknown"));
+        exit(1)
+    }
+}
+
+/// Removes the option and its argument from the arguments, returning the argument; options end at
+/// a `--`, after which the arguments are a program's.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let options_end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    let i = args[..options_end].iter().position(|arg| arg == name)?;
+    args.remove(i);
+    if i + 1 == options_end || args[i].starts_with('-') { die!("{} requires an argument", name); }
+    Some(args.remove(i))
+}
+
+/// Starts the server the way BSP clients do, by running the `argv` in `.bsp/sbt.json`, and talking
+/// BSP over its stdin and stdout.
+fn spawn_from_connection_file() -> std::process::Child {
+    let path = project::path(".bsp/sbt.json");
+    let json: Value = File::open(&path).ok().and_then(|f| serde_json::from_reader(f).ok()).unwrap_or_else(|| {
+        die!("no sbt server is running and there's no readable {} (run `sbt bspConfig` to create it)", path.display());
+    });
+    let argv: Vec<&str> = json["argv"].as_array().map(|argv| argv.iter().filter_map(Value::as_str).collect()).unwrap_or_default();
+    if argv.is_empty() { die!("no argv in {}", path.display()); }
+    Command::new(argv[0]).args(&argv[1..])
+        .current_dir(&*project::ROOT)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| { die!("failed to run {}: {}", argv[0], e); })
+}
+
+/// Initializes the session and performs the action, returning what to exit with.
+fn drive<R: BufRead, W: Write>(session: &mut Session<R, W>, action: &Action, output: &mut Output) -> i32 {
+    let id = session.send_request("build/initialize", &json!({
+        "displayName": "sbtl",
+        "version": env!("CARGO_PKG_VERSION"),
+        "bspVersion": BSP_VERSION,
+        "rootUri": client::file_uri(&project::ROOT),
+        "capabilities": {"languageIds": ["scala", "java"]},
+    }));
+    session.await_response(id).unwrap_or_else(|e| { die!("failed to initialize the BSP session: {}", e.message); });
+    session.send_notification("build/initialized", &json!({}));
+
+    let id = session.send_request("workspace/buildTargets", &Value::Null);
+    let result = session.await_response(id).unwrap_or_else(|e| { die!("failed to list the build targets: {}", e.message); });
+    let targets: Vec<BuildTarget> = result["targets"].as_array().map(|ts| ts.iter().filter_map(BuildTarget::from_json).collect()).unwrap_or_default();
+
+    let select = |names: &[String], capable| select(&targets, names, capable).unwrap_or_else(|e| { die!("{}", e); });
+    let (method, params) = match action {
+        Action::Targets              => {
+            for target in &targets {
+                let can = [("compile", target.can_compile), ("test", target.can_test), ("run", target.can_run)];
+                let can: Vec<&str> = can.iter().filter(|(_, can)| *can).map(|(what, _)| *what).collect();
+                println!("{} ({})", target.name, can.join(", "));
+            }
+            return 0
+        },
+        Action::Compile(names)       => {
+            let ids: Vec<Value> = select(names, |t| t.can_compile).iter().map(|t| t.id()).collect();
+            ("buildTarget/compile", json!({"targets": ids, "originId": origin_id()}))
+        },
+        Action::Test(names)          => {
+            let ids: Vec<Value> = select(names, |t| t.can_test).iter().map(|t| t.id()).collect();
+            ("buildTarget/test", json!({"targets": ids, "originId": origin_id()}))
+        },
+        Action::Run(name, arguments) => {
+            let runnable = select(&name.iter().cloned().collect::<Vec<_>>(), |t| t.can_run);
+            let target = match runnable.as_slice() {
+                [target] => target,
+                []       => { die!("no build target can run"); },
+                _        => { die!("more than one build target can run, pick one of: {}", runnable.iter().map(|t| t.name.as_str()).collect::<Vec<_>>().join(", ")); },
+            };
+            ("buildTarget/run", json!({"target": target.id(), "originId": origin_id(), "arguments": arguments}))
+        },
+    };
+
+    let id = session.send_request(method, &params);
+    match session.await_response_with(id, |method, params| output.on_notification(method, params)) {
+        Ok(result) => exit_code(&result),
+        Err(e)     => { output.progress.clear(); eprintln!("[error] {} failed: {}", method, e.message); 1 },
+    }
+}
+
+/// Tags the notifications about our request, which is `originId`'s purpose.
+fn origin_id() -> String { format!("sbtl-{}", std::process::id()) }
+
+fn exit_code(result: &Value) -> i32 {
+    match result["statusCode"].as_i64() {
+        Some(STATUS_OK)        => 0,
+        Some(STATUS_CANCELLED) => 130,
+        _                      => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Action { Action::parse(args.iter().map(|s| s.to_string())) }
+
+    fn targets() -> Vec<BuildTarget> {
+        [
+            json!({"id": {"uri": "file:/p/#root/Compile"}, "displayName": "root", "capabilities": {"canCompile": true, "canTest": false, "canRun": true}}),
+            json!({"id": {"uri": "file:/p/#root/Test"}, "displayName": "root-test", "capabilities": {"canCompile": true, "canTest": true, "canRun": true}}),
+            json!({"id": {"uri": "file:/p/#build"}, "capabilities": {"canCompile": true}}),
+        ].iter().filter_map(BuildTarget::from_json).collect()
+    }
+
+    #[test]
+    fn parse_actions() {
+        assert_eq!(args(&["compile"]), Action::Compile(vec![]));
+        assert_eq!(args(&["test", "root-test"]), Action::Test(vec!["root-test".to_owned()]));
+        assert_eq!(args(&["run", "root", "--", "a", "b"]), Action::Run(Some("root".to_owned()), vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(args(&["run", "--", "a"]), Action::Run(None, vec!["a".to_owned()]));
+        assert_eq!(args(&["run", "root", "--", "--", "x"]), Action::Run(Some("root".to_owned()), vec!["--".to_owned(), "x".to_owned()]));
+    }
+
+    #[test]
+    fn options_end_at_double_dash() {
+        let mut args: Vec<String> = ["test", "--junit-xml", "out", "root-test"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(take_option(&mut args, "--junit-xml"), Some("out".to_owned()));
+        assert_eq!(args, vec!["test", "root-test"]);
+        let mut args: Vec<String> = ["run", "root", "--", "--junit-xml", "out"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(take_option(&mut args, "--junit-xml"), None);
+        assert_eq!(args.len(), 5);
+    }
+
+    #[test]
+    fn build_targets() {
+        let targets = targets();
+        assert_eq!(targets[2].name, "file:/p/#build"); // no displayName
+        let names = |ts: Vec<&BuildTarget>| ts.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(select(&targets, &[], |t| t.can_compile).unwrap()).len(), 3);
+        assert_eq!(names(select(&targets, &[], |t| t.can_test).unwrap()), vec!["root-test"]);
+        assert_eq!(names(select(&targets, &["file:/p/#root/Compile".to_owned()], |t| t.can_run).unwrap()), vec!["root"]);
+        assert!(select(&targets, &["root".to_owned()], |t| t.can_test).is_err());
+        assert!(select(&targets, &["nope".to_owned()], |t| t.can_compile).unwrap_err().contains("root, root-test"));
+    }
+
+    #[test]
+    fn progress() {
+        assert_eq!(format_progress(&json!({"message": "Compiling root", "progress": 3, "total": 10, "unit": "files"})), "Compiling root (3/10 files)");
+        assert_eq!(format_progress(&json!({"message": "Compiling root"})), "Compiling root");
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(exit_code(&json!({"statusCode": 1})), 0);
+        assert_eq!(exit_code(&json!({"statusCode": 2})), 1);
+        assert_eq!(exit_code(&json!({"statusCode": 3})), 130);
+    }
+}
@@ -9,172 +9,559 @@ use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs;
 use std::fs::File;
+use std::io;
+use std::io::IsTerminal;
 use std::io::{ BufReader, BufWriter, };
 use std::io::prelude::*;
+use std::net::{ Shutdown, TcpStream, };
 use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
 use std::path::{ Path, PathBuf, };
-use std::process::{ Command, exit, };
+use std::process::{ self, Command, exit, };
+use std::sync::{ Arc, Mutex, mpsc, };
+use std::thread;
+use std::time::{ Duration, Instant, };
 
-use jsonrpc_lite;
 use jsonrpc_lite::JsonRpc;
 
-use serde_json;
 use serde_json::Value;
 
 use void::Void;
 
-type JsonRpcId = i64;
+use crate::codec::{ CodecError, MessageReader, MessageWriter, };
+use crate::diagnostics;
+use crate::diagnostics::{ FileDiagnostics, Renderer, };
+use crate::launcher;
+use crate::log;
+use crate::log::Logger;
+use crate::project;
+use crate::sbt_client;
+use crate::sbt_client::ExecResult;
+use crate::server;
+use crate::test_report;
+use crate::test_report::TestReport;
+use crate::timings;
+use crate::watch;
+use crate::watch::Watch;
 
-fn make_lsp_json_str(id: JsonRpcId, method: &str, params: &Value) -> Result<String, serde_json::error::Error> {
-    let msg = json!({
+pub type JsonRpcId = i64;
+
+/// Where a running sbt server says how to connect to it, relative to the project root.
+pub const PORT_FILE: &str = "project/target/active.json";
+
+/// Where a server started by `--client` writes its output.
+pub const SERVER_LOG: &str = "project/target/sbtl-server.log";
+
+/// Where a server started by `--client` has its pid recorded, as the port file doesn't.
+const SERVER_PID: &str = "project/target/sbtl-server.pid";
+
+/// How long to wait for a port file that's there to be written in full.
+const PORT_FILE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait for a server started by `--client` to be ready, which can take a while
+/// the first time, when sbt has a lot to resolve.
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How long the server can be quiet before we warn that it might be stuck.
+const IDLE_WARNING: Duration = Duration::from_secs(30);
+
+/// How often to wake up from waiting on the server, to check how long it's been quiet.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// How long to wait to connect to the server, unless `-connect-timeout` says otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a server that's accepted a connection has to answer us, unless `-read-timeout` says otherwise.
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What we exit with when the server goes away mid-command (`EX_UNAVAILABLE`).
+const EXIT_SERVER_LOST: i32 = 69;
+
+/// What we exit with when the server's been quiet for longer than the `-read-timeout`, like `timeout` does.
+const EXIT_SERVER_UNRESPONSIVE: i32 = 124;
+
+/// What we exit with when the command's cancelled, as a shell does for a process SIGINT killed.
+const EXIT_INTERRUPTED: i32 = 130;
+
+lazy_static! {
+    static ref CONNECT_TIMEOUT: Duration = timeout_arg("-connect-timeout").unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    /// How long the server can be quiet before we give up on it, if at all.
+    static ref READ_TIMEOUT: Option<Duration> = timeout_arg("-read-timeout");
+}
+
+fn timeout_arg(name: &str) -> Option<Duration> {
+    launcher::option_arg(env::args().skip(1), name).map(|secs| match secs.parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_)   => { die!("{} requires <seconds> argument, not {}", name, secs); },
+    })
+}
+
+/// How long to wait for the server to acknowledge a cancellation before giving up on it.
+const CANCEL_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The JSON-RPC error code the server responds with when a request was cancelled.
+pub const REQUEST_CANCELLED: i64 = -32800;
+
+/// The JSON-RPC error code we respond with to requests for methods we don't support.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+
+pub fn request(id: JsonRpcId, method: &str, params: &Value) -> Value {
+    json!({
         "jsonrpc": "2.0",
         "id": id,
         "method": method,
         "params": params,
-    });
-    let request = serde_json::to_string(&msg)?;
-    Ok(format!("Content-Length: {}\r\n\r\n{}", request.len(), request))
+    })
 }
 
-#[derive(Debug, PartialEq)]
-/// A message header, as described in the Language Server Protocol specification.
-enum LspHeader {
-    ContentType,
-    ContentLength(usize),
+pub fn notification(method: &str, params: &Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+    })
 }
 
-/// Given a reference to a reader, attempts to read a Language Server Protocol message,
-/// blocking until a message is received.
-fn read_message<B: BufRead>(reader: &mut B) -> Value {
-    let mut buffer = String::new();
-    let mut content_length: Option<usize> = None;
+/// Where the sbt server is listening, as advertised by the `uri` in `project/target/active.json`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerUri {
+    /// `local:///path/to/socket`, a Unix domain socket.
+    Local(PathBuf),
+    /// `tcp://host:port`, as used by sbt 1.0.x and 1.1.x.
+    Tcp(String),
+}
 
-    // read in headers.
-    loop {
-            buffer.clear();
-            reader.read_line(&mut buffer).unwrap();
-            match &buffer {
-                s if s.trim().is_empty() => { break }, // empty line is end of headers
-                s => {
-                    match parse_header(s) {
-                        LspHeader::ContentLength(len) => content_length = Some(len),
-                        LspHeader::ContentType => (), // utf-8 only currently allowed value
-                    };
-                }
-            };
+impl ServerUri {
+    pub fn parse(uri: &str) -> Result<ServerUri, String> {
+        if let Some(path) = uri.strip_prefix("local://") {
+            if path.is_empty() { return Err(format!("malformed server URI (no socket path): {}", uri)) }
+            Ok(ServerUri::Local(PathBuf::from(path)))
+        } else if let Some(authority) = uri.strip_prefix("tcp://") {
+            let authority = authority.trim_end_matches('/');
+            match authority.rfind(':') {
+                Some(i) if i > 0 && authority[i + 1..].parse::<u16>().is_ok() => Ok(ServerUri::Tcp(authority.to_owned())),
+                _ => Err(format!("malformed server URI (expected tcp://host:port): {}", uri)),
+            }
+        } else if uri.starts_with("local:") {
+            Err(format!("unsupported server URI (named pipes aren't supported): {}", uri))
+        } else {
+            let scheme = uri.split(':').next().unwrap_or(uri);
+            Err(format!("unsupported server URI scheme '{}': {}", scheme, uri))
+        }
+    }
+}
+
+impl Display for ServerUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerUri::Local(path) => write!(f, "local://{}", path.display()),
+            ServerUri::Tcp(addr)   => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+/// A connection to an sbt server, over whichever transport its `ServerUri` calls for.
+pub enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    pub fn connect(uri: &ServerUri) -> io::Result<Connection> {
+        Connection::connect_within(uri, *CONNECT_TIMEOUT)
+    }
+
+    /// Connects on another thread, so we can give up on a server that doesn't accept the
+    /// connection in time, whatever the transport.
+    pub fn connect_within(uri: &ServerUri, timeout: Duration) -> io::Result<Connection> {
+        let (tx, rx) = mpsc::channel();
+        let uri = uri.clone();
+        thread::spawn(move || {
+            let _ = tx.send(match &uri {
+                ServerUri::Local(path) => UnixStream::connect(path).map(Connection::Unix),
+                ServerUri::Tcp(addr)   => TcpStream::connect(addr.as_str()).map(Connection::Tcp),
+            });
+        });
+        rx.recv_timeout(timeout).unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting after {}s", timeout.as_secs())))
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+            Connection::Tcp(stream)  => stream.try_clone().map(Connection::Tcp),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.shutdown(how),
+            Connection::Tcp(stream)  => stream.shutdown(how),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            Connection::Tcp(stream)  => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// The pid of the server on the other end, which only Unix sockets can tell us.
+    pub fn peer_pid(&self) -> Option<u32> {
+        match self {
+            Connection::Unix(stream) => peer_pid(stream),
+            Connection::Tcp(_)       => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_pid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if ret == 0 && cred.pid > 0 { Some(cred.pid as u32) } else { None }
+}
+
+#[cfg(target_os = "macos")]
+fn peer_pid(stream: &UnixStream) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+    let mut pid: libc::pid_t = 0;
+    let mut len = std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_LOCAL, libc::LOCAL_PEERPID, &mut pid as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if ret == 0 && pid > 0 { Some(pid as u32) } else { None }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn peer_pid(_stream: &UnixStream) -> Option<u32> { None }
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tcp(stream)  => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tcp(stream)  => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tcp(stream)  => stream.flush(),
+        }
+    }
+}
+
+/// What the server we're talking to supports, as far as we can tell.
+///
+/// sbt doesn't advertise its custom methods as capabilities, so unless the `initialize` response
+/// says otherwise we go by the version of sbt the server's running: `sbt/completion`,
+/// `sbt/cancelRequest`, BSP, the `exitCode` in `sbt/exec` responses and the forwarding of
+/// the terminal's IO (`sbt/attach`) all arrived in sbt 1.4.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ServerFeatures {
+    pub(crate)   completion: bool,
+    pub(crate) cancellation: bool,
+    pub(crate)          bsp: bool,
+    pub(crate)    exit_code: bool,
+    pub(crate)     terminal: bool,
+}
+
+impl ServerFeatures {
+    pub(crate) fn new(capabilities: &Value, sbt_version: &str) -> ServerFeatures {
+        let modern = sbt_version_at_least(sbt_version, 1, 4);
+        let flag = |name: &str| match &capabilities[name] {
+            Value::Null      => modern,
+            Value::Bool(b)   => *b,
+            _                => true,
+        };
+        ServerFeatures {
+              completion: flag("completionProvider"),
+            cancellation: flag("cancelRequestProvider"),
+                     bsp: flag("bspProvider"),
+               exit_code: modern,
+                terminal: modern,
+        }
+    }
+}
+
+/// Whether the given sbt version is at least `major.minor`; unparseable versions are assumed to be old.
+fn sbt_version_at_least(version: &str, major: u32, minor: u32) -> bool {
+    let mut parts = version.split(['.', '-']).map(|s| s.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(ma)), Some(Ok(mi))) => (ma, mi) >= (major, minor),
+        _                            => false,
+    }
+}
+
+/// Makes a `file://` URI for an absolute path, percent-encoding anything outside of the unreserved set.
+pub fn file_uri(path: &Path) -> String {
+    format!("file://{}", uri_path(path))
+}
+
+/// The path as the path of a URI, e.g. a relative reference, percent-encoding anything outside of
+/// the unreserved set.
+pub fn uri_path(path: &Path) -> String {
+    let mut uri = String::new();
+    for b in path.to_string_lossy().bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(b as char),
+            _ => uri.push_str(&format!("%{:02X}", b)),
         }
+    }
+    uri
+}
 
-    let content_length = content_length.ok_or_else(|| format!("missing content-length header: {}", buffer)).unwrap();
-    // message body isn't newline terminated, so we read content_length bytes
-    let mut body_buffer = vec![0; content_length];
-    reader.read_exact(&mut body_buffer).unwrap();
-    let body = String::from_utf8(body_buffer).unwrap();
-    serde_json::from_str::<Value>(&body).unwrap()
+/// Reads from the server, warning when it's gone quiet and, given a read timeout, giving up on it
+/// once it's been quiet for that long. It relies on the connection timing out its reads every
+/// `HEARTBEAT` to get the chance to.
+pub struct Heartbeat<R> {
+           inner: R,
+    read_timeout: Option<Duration>,
 }
 
-const HEADER_CONTENT_LENGTH: &str = "content-length";
-const HEADER_CONTENT_TYPE: &str = "content-type";
+impl<R> Heartbeat<R> {
+    fn new(inner: R, read_timeout: Option<Duration>) -> Heartbeat<R> {
+        Heartbeat { inner, read_timeout }
+    }
+}
 
-/// Given a header string, attempts to extract and validate the name and value parts.
-fn parse_header(s: &str) -> LspHeader {
-    let split: Vec<String> = s.split(": ").map(|s| s.trim().to_lowercase()).collect();
-    if split.len() != 2 { panic!(format!("malformed header: {}", s)) }
-    match split[0].as_ref() {
-        HEADER_CONTENT_TYPE   => LspHeader::ContentType,
-        HEADER_CONTENT_LENGTH => LspHeader::ContentLength(usize::from_str_radix(&split[1], 10).unwrap()),
-        _ => panic!(format!("Unknown header: {}", s)),
+impl<R: Read> Read for Heartbeat<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let quiet_since = Instant::now();
+        let mut warned = false;
+        loop {
+            match self.inner.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    let quiet = quiet_since.elapsed();
+                    if let Some(timeout) = self.read_timeout.filter(|timeout| quiet >= *timeout) {
+                        let msg = format!("the sbt server was unresponsive for {}s, giving up on it", timeout.as_secs());
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, msg))
+                    }
+                    if !warned && quiet >= IDLE_WARNING {
+                        eprintln!("[warn] the sbt server has been unresponsive for {}s", quiet.as_secs());
+                        warned = true;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                result                                               => return result,
+            }
+        }
     }
 }
 
-fn handle_msg_quietly<B: BufRead>(mut reader: B) {
-    match serde_json::from_value(read_message(&mut reader)).unwrap() {
-        JsonRpc::Request(obj)    => eprintln!("client received unexpected request: {:?}", obj),
-        JsonRpc::Notification(_) => (),
-        JsonRpc::Success(_)      => (),
-        JsonRpc::Error(obj)      => println!("recv error: {:?}", obj),
+/// A connection that several threads can write whole messages to, e.g. to cancel a request
+/// or forward stdin while the main thread carries on reading.
+#[derive(Clone)]
+pub struct SharedWriter(Arc<Mutex<Connection>>);
+
+impl SharedWriter {
+    pub fn new(stream: Connection) -> SharedWriter { SharedWriter(Arc::new(Mutex::new(stream))) }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().shutdown(how)
+    }
+
+    /// Sets the read timeout of the connection, which its clones, e.g. for reading, share.
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().set_read_timeout(timeout)
     }
 }
 
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> { self.0.lock().unwrap().write_all(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.0.lock().unwrap().flush() }
+}
+
+/// Reads the authentication token out of the token file that TCP servers advertise via `tokenfilePath`.
+pub fn read_token(token_file_path: &str) -> String {
+    try_read_token(token_file_path).unwrap_or_else(|e| { die!("{}", e); })
+}
 
-fn handle_msg<B: BufRead>(mut reader: B) {
-    match serde_json::from_value(read_message(&mut reader)).unwrap() {
-        JsonRpc::Request(obj)      => eprintln!("client received unexpected request: {:?}", obj),
-        JsonRpc::Notification(obj) => println!("recv notification: {:?}", obj),
-        JsonRpc::Success(obj)      => println!("recv success: {:?}", obj),
-        JsonRpc::Error(obj)        => println!("recv error: {:?}", obj),
+pub fn try_read_token(token_file_path: &str) -> Result<String, String> {
+    let token_file = File::open(token_file_path)
+        .map_err(|e| format!("failed to open the server token file {}: {}", token_file_path, e))?;
+    let json: Value = serde_json::from_reader(token_file)
+        .map_err(|e| format!("failed to parse the server token file {}: {}", token_file_path, e))?;
+    match json["token"].as_str() {
+        Some(token) => Ok(token.to_owned()),
+        None        => Err(format!("no token in the server token file {}", token_file_path)),
     }
 }
 
-enum ExitCode { Success, Failure }
+/// Reads the next JSON-RPC message from the server, giving up if the connection is broken, with
+/// distinct exit codes for a server that's gone away and one that's stopped responding.
+fn read_json_rpc<B: BufRead>(reader: &mut MessageReader<B>) -> JsonRpc {
+    match reader.read_message() {
+        Ok(Some(json)) => serde_json::from_value(json)
+            .unwrap_or_else(|e| { die!("received a message from the sbt server that isn't JSON-RPC: {}", e); }),
+        Ok(None) | Err(CodecError::UnexpectedEof) => {
+            eprintln!("[error] the sbt server closed the connection, it may have crashed or been stopped");
+            exit(EXIT_SERVER_LOST)
+        },
+        Err(CodecError::Io(ref e)) if server_lost(e) => {
+            eprintln!("[error] lost the connection to the sbt server: {}", e);
+            exit(EXIT_SERVER_LOST)
+        },
+        Err(CodecError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+            eprintln!("[error] {}", e);
+            exit(EXIT_SERVER_UNRESPONSIVE)
+        },
+        Err(e)         => { die!("failed to read a message from the sbt server: {}", e); },
+    }
+}
+
+fn server_lost(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe)
+}
 
-fn handle_msg_to_exit_code<B: BufRead>(mut reader: B, id: JsonRpcId) -> ExitCode {
+/// How a command run through `sbt/exec` ended.
+#[derive(Debug, PartialEq)]
+enum ExitCode { Success, Failure(i32), Cancelled }
+
+impl ExitCode {
+    fn from_code(code: i32) -> ExitCode {
+        match code {
+            0    => ExitCode::Success,
+            code => ExitCode::Failure(code),
+        }
+    }
+
+    /// The exit code sbt reported in an `sbt/exec` result or error, as in `{"status":"Done","exitCode":0}`.
+    fn from_exec_result(result: &Value) -> Option<ExitCode> {
+        ExecResult::from_result(result.clone()).exit_code.map(ExitCode::from_code)
+    }
+
+    /// What sbtl exits with; stopping a watch is how it's meant to end, so that's a success.
+    fn status(&self, continuous: bool) -> i32 {
+        match self {
+            ExitCode::Success                 => 0,
+            ExitCode::Failure(code)           => *code,
+            ExitCode::Cancelled if continuous => 0,
+            ExitCode::Cancelled               => EXIT_INTERRUPTED,
+        }
+    }
+}
+
+/// The params of a request or notification as JSON, `null` if there aren't any.
+fn params_of(json_rpc: &JsonRpc) -> Value {
+    match json_rpc.get_params() {
+        Some(jsonrpc_lite::Params::Map(kvs))  => Value::Object(kvs),
+        Some(jsonrpc_lite::Params::Array(vs)) => Value::Array(vs),
+        _                                     => Value::Null,
+    }
+}
+
+/// An id as it's written in JSON, for responding to a request with the same id it came with.
+fn id_to_json(id: jsonrpc_lite::Id) -> Value {
+    match id {
+        jsonrpc_lite::Id::Num(n)   => json!(n),
+        jsonrpc_lite::Id::Str(s)   => json!(s),
+        jsonrpc_lite::Id::None(()) => Value::Null,
+    }
+}
+
+fn id_to_string(id: jsonrpc_lite::Id) -> String {
+    match id {
+        jsonrpc_lite::Id::Num(n)   => n.to_string(),
+        jsonrpc_lite::Id::Str(s)   => s,
+        jsonrpc_lite::Id::None(()) => "".to_string(),
+    }
+}
+
+/// Reads messages until the command run by the `sbt/exec` request with the given id finishes.
+///
+/// The exit code is taken from the `sbt/exec` response; servers that don't report one
+/// (see `ServerFeatures::exit_code`) leave us guessing it from their log messages.
+fn handle_msg_to_exit_code<R: BufRead, W: Write>(session: &mut Session<R, W>, id: JsonRpcId, cancel_id: JsonRpcId, output: &mut Output) -> ExitCode {
     let mut done = false;
     let mut success = false;
     let mut failure = false;
-
     loop {
-        let json_rpc: JsonRpc = serde_json::from_value(read_message(&mut reader)).unwrap();
+        let json_rpc = read_json_rpc(&mut session.reader);
         match json_rpc {
-            JsonRpc::Request(ref obj)      => eprintln!("client received unexpected request: {:?}", obj),
+            JsonRpc::Request(_)            => session.handle_request(&json_rpc),
             JsonRpc::Success(ref obj)      => {
-                let json_id = json_rpc.get_id().unwrap();
-                let id_str = match json_id {
-                    jsonrpc_lite::Id::Num(n)   => n.to_string(),
-                    jsonrpc_lite::Id::Str(s)   => s,
-                    jsonrpc_lite::Id::None(()) => "".to_string(),
-                };
+                let id_str = id_to_string(json_rpc.get_id().unwrap());
                 if id_str == id.to_string() {
-                    return ExitCode::Success
+                    let result = json_rpc.get_result().unwrap();
+                    return match ExitCode::from_exec_result(result) {
+                        Some(exit_code)   => exit_code,
+                        None if failure   => ExitCode::Failure(1),
+                        None              => ExitCode::Success,
+                    }
+                } else if id_str == cancel_id.to_string() {
+                    return ExitCode::Cancelled
                 } else {
-                    println!("recv success: {:?}", obj)
+                    let _ = writeln!(output.human, "recv success: {:?}", obj);
                 }
             },
             JsonRpc::Error(ref obj)        => {
-                let json_id = json_rpc.get_id().unwrap();
-                let id_str = match json_id {
-                    jsonrpc_lite::Id::Num(n)   => n.to_string(),
-                    jsonrpc_lite::Id::Str(s)   => s,
-                    jsonrpc_lite::Id::None(()) => "".to_string(),
-                };
-                if id_str == id.to_string() {
-                    let error = json_rpc.get_error().unwrap();
+                let id_str = id_to_string(json_rpc.get_id().unwrap());
+                let error = json_rpc.get_error().unwrap();
+                if id_str == id.to_string() && error.code == REQUEST_CANCELLED {
+                    return ExitCode::Cancelled
+                } else if id_str == id.to_string() {
                     eprintln!("[error] {}", error);
-                    return ExitCode::Failure
+                    let exit_code = error.data.as_ref().and_then(ExitCode::from_exec_result);
+                    return exit_code.unwrap_or(ExitCode::Failure(1))
+                } else if id_str == cancel_id.to_string() {
+                    eprintln!("[error] failed to cancel: {}", error);
+                    return ExitCode::Cancelled
                 } else {
-                    println!("recv error: {:?}", obj)
+                    let _ = writeln!(output.human, "recv error: {:?}", obj);
                 }
             },
             JsonRpc::Notification(ref obj) => {
                 match json_rpc.get_method() {
                     Some("window/logMessage") => {
-                        let params0 = json_rpc.get_params().unwrap();
-                        let params = match params0 {
-                            jsonrpc_lite::Params::Array(_) => panic!("not expecting array"),
-                            jsonrpc_lite::Params::None(()) => panic!("not expecting none"),
-                            jsonrpc_lite::Params::Map(kvs) => kvs,
+                        let params = params_of(&json_rpc);
+                        let (lvl, msg) = match (params["type"].as_i64(), params["message"].as_str()) {
+                            (Some(lvl), Some(msg)) => (lvl, msg),
+                            _                      => { eprintln!("client received malformed log message: {:?}", obj); continue },
                         };
-                        let lvl = params["type"].as_i64().unwrap();
-                        let msg = params["message"].as_str().unwrap();
-                        println!("{}", msg);
+                        let level = log::Level::from_lsp(lvl, msg);
+                        if let Some(separator) = output.watch.as_mut().and_then(|watch| watch.before(msg)) {
+                            output.renderer.reset(); // the problems of this build are the ones that matter
+                            if let Some(tests) = output.tests.as_mut() { *tests = TestReport::default() }
+                            let _ = writeln!(output.human, "{}", separator);
+                        }
+                        output.logger.log_to(&mut output.human, level, msg);
+                        if let Some(tests) = output.tests.as_mut() { tests.on_log(msg) }
+                        if let Some(result) = output.watch.as_mut().and_then(|watch| watch.after(level, msg)) {
+                            let _ = writeln!(output.human, "{}", result);
+                        }
                         if msg == "Exited with code 0" { success = true }
                         if msg == "Done" { done = true }
                         if lvl == 1 { failure = true }
                     },
+                    Some("sbt/systemOut") => forward_output(&mut output.human, &params_of(&json_rpc)),
+                    Some("sbt/systemErr") => forward_output(&mut io::stderr(), &params_of(&json_rpc)),
+                    Some("sbt/systemOutFlush") => { let _ = output.human.flush(); },
+                    Some("sbt/systemErrFlush") => { let _ = io::stderr().flush(); },
                     Some("textDocument/publishDiagnostics") => {
-                        let params0 = json_rpc.get_params().unwrap();
-                        let params = match params0 {
-                            jsonrpc_lite::Params::Array(_) => panic!("not expecting array"),
-                            jsonrpc_lite::Params::None(()) => panic!("not expecting none"),
-                            jsonrpc_lite::Params::Map(kvs) => kvs,
-                        };
-                        let uri = params["uri"].as_str().unwrap();
-                        let diagnostics = params["diagnostics"].as_array().unwrap();
-                        for diagnostic in diagnostics {
-                            println!("{}: {}", uri, diagnostic);
+                        match FileDiagnostics::from_params(&params_of(&json_rpc)) {
+                            Some(file) => { let _ = write!(output.human, "{}", output.renderer.render(&file)); },
+                            None       => eprintln!("client received malformed diagnostics: {:?}", obj),
                         }
                     },
-                    Some(_) | _ => println!("recv notification: {:?}", obj),
+                    _ => { let _ = writeln!(output.human, "recv notification: {:?}", obj); },
                 }
             },
         }
@@ -189,58 +576,1230 @@ fn handle_msg_to_exit_code<B: BufRead>(mut reader: B, id: JsonRpcId) -> ExitCode
         // 'compile' w/ error   reports "[error] Compilation failed"
         // 'compile' w/o errors reports "[log] Done", but we can't distinguish that from BadMain's "[log] Done"...
 
-        if success && done { return ExitCode::Success }
-        if failure { return ExitCode::Failure }
+        // a watch carries on after failures, so it only ends with the response
+        if !session.features.exit_code && output.watch.is_none() {
+            if success && done { return ExitCode::Success }
+            if failure { return ExitCode::Failure(1) }
+        }
+    }
+}
+
+/// A server that's listening, as advertised by its port file.
+pub struct RunningServer {
+       pub uri: ServerUri,
+    pub stream: Connection,
+     pub token: Option<String>,
+}
+
+/// What's become of the server a port file advertises.
+enum Liveness {
+    /// It's answering, and here's a connection to it.
+    Alive(Connection),
+    /// It's definitely gone: nothing's listening and, if we know its pid, that process is dead.
+    Stale,
+    /// We can't tell, e.g. we're not allowed to connect to it, or it's not answering.
+    Unknown(io::Error),
+}
+
+fn liveness(uri: &ServerUri, token: Option<&str>, pid: Option<u32>, timeout: Duration) -> Liveness {
+    match Connection::connect(uri) {
+        Ok(stream)                                                         => {
+            match probe(stream, token, timeout).and_then(|()| Connection::connect(uri)) {
+                Ok(stream) => Liveness::Alive(stream),
+                Err(e)     => Liveness::Unknown(e),
+            }
+        },
+        Err(ref e) if nothing_listening(e) && !pid.is_some_and(server::is_alive) => Liveness::Stale,
+        Err(e)                                                             => Liveness::Unknown(e),
+    }
+}
+
+/// Checks the server answers an `initialize` within the timeout, on a connection of its own, as
+/// a server that's wedged can still have connections to it accepted, by the OS.
+fn probe(stream: Connection, token: Option<&str>, timeout: Duration) -> io::Result<()> {
+    let unresponsive = || io::Error::new(io::ErrorKind::TimedOut, format!("the sbt server didn't respond within {}s", timeout.as_secs_f64()));
+    let codec_error = |e: CodecError| match e {
+        CodecError::Io(e) => e,
+        e                 => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    };
+    let deadline = Instant::now() + timeout;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reader = MessageReader::new(BufReader::new(stream.try_clone()?));
+    let mut writer = MessageWriter::new(stream);
+    writer.write_message(&request(1, "initialize", &sbt_client::initialize_params(token, &project::ROOT))).map_err(codec_error)?;
+    let is_response = |msg: &Value| msg.get("method").is_none() && msg.get("id").and_then(sbt_client::response_id) == Some(1);
+    let timed_out = |e: &io::Error| e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut;
+    let answered = loop {
+        match reader.read_message() {
+            Ok(Some(ref msg)) if is_response(msg)          => break Ok(()),
+            Ok(Some(_)) if Instant::now() < deadline       => (), // e.g. a log message
+            Ok(Some(_))                                    => break Err(unresponsive()),
+            Ok(None) | Err(CodecError::UnexpectedEof)      => {
+                break Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the sbt server closed the connection"))
+            },
+            Err(CodecError::Io(ref e)) if timed_out(e)     => break Err(unresponsive()),
+            Err(e)                                         => break Err(codec_error(e)),
+        }
+    };
+    let _ = writer.get_ref().shutdown(Shutdown::Both);
+    answered
+}
+
+/// Whether a connect error means there's no server there: the socket file's gone or nothing's
+/// accepting connections on it (or on the port).
+fn nothing_listening(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused)
+}
+
+/// A server the port file advertises that we can't tell the state of, e.g. as it's not answering.
+pub struct UnknownServer {
+      pub uri: ServerUri,
+    pub error: io::Error,
+}
+
+/// Finds the server the port file advertises, if there's one running, only removing the port file
+/// if it's definitely stale.
+pub fn find_server() -> Option<RunningServer> {
+    locate_server().unwrap_or_else(|server| {
+        die!("failed to connect to the sbt server at {}: {} (delete {} if it isn't running)",
+            server.uri, server.error, project::path(PORT_FILE).display());
+    })
+}
+
+/// Finds the server as `find_server` does, but leaves what to do about one that isn't answering to
+/// the caller, e.g. `sbtl server stop`, which can still kill it.
+pub fn locate_server() -> Result<Option<RunningServer>, UnknownServer> {
+    let (contents, json) = match read_port_file() { Some(port_file) => port_file, None => return Ok(None) };
+    let uri = json["uri"].as_str().unwrap_or_else(|| { die!("no uri in {}", project::path(PORT_FILE).display()); });
+    let server_uri = ServerUri::parse(uri).unwrap_or_else(|e| { die!("{}", e); });
+    // a stale server's token file might well be gone, which only matters if it's not stale
+    let probe_token = json["tokenfilePath"].as_str().and_then(|path| try_read_token(path).ok());
+    match liveness(&server_uri, probe_token.as_deref(), server_pid(), probe_timeout()) {
+        Liveness::Alive(stream) => {
+            let token = json["tokenfilePath"].as_str().map(read_token);
+            Ok(Some(RunningServer { uri: server_uri, stream, token }))
+        },
+        Liveness::Stale         => { remove_stale_port_file(&contents); Ok(None) },
+        Liveness::Unknown(e)    => Err(UnknownServer { uri: server_uri, error: e }),
+    }
+}
+
+/// How long to give the server to answer before taking it to be wedged.
+pub fn probe_timeout() -> Duration { READ_TIMEOUT.unwrap_or(DEFAULT_PROBE_TIMEOUT) }
+
+/// Reads the port file, if there is one, giving the server a moment to finish writing it.
+fn read_port_file() -> Option<(String, Value)> {
+    let started = Instant::now();
+    loop {
+        let contents = fs::read_to_string(project::path(PORT_FILE)).ok()?;
+        match serde_json::from_str(&contents) {
+            Ok(json)                                       => return Some((contents, json)),
+            Err(_) if started.elapsed() < PORT_FILE_TIMEOUT => thread::sleep(Duration::from_millis(100)),
+            Err(e)                                         => { die!("failed to parse {}: {}", project::path(PORT_FILE).display(), e); },
+        }
+    }
+}
+
+/// Removes the port file, unless it's been replaced since, by a server that's just started.
+fn remove_stale_port_file(contents: &str) {
+    let port_file = project::path(PORT_FILE);
+    if fs::read_to_string(&port_file).ok().as_deref() == Some(contents) {
+        let _ = fs::remove_file(port_file);
+        forget_server_pid();
+    }
+}
+
+/// The pid of the server `--client` started, if it's been recorded.
+pub fn server_pid() -> Option<u32> {
+    fs::read_to_string(project::path(SERVER_PID)).ok()?.trim().parse().ok()
+}
+
+/// Removes the pid of the server `--client` started, once that server's gone, so the pid isn't
+/// taken for a live server's after it's been reused.
+pub fn forget_server_pid() {
+    let _ = fs::remove_file(project::path(SERVER_PID));
+}
+
+pub fn talk_to_client(server: RunningServer) {
+    talk_to_client_impl(server.stream, server.token)
+}
+
+/// Starts an sbt server in the background, like `sbt --client` does, waits for it to be
+/// listening and then runs the command on it.
+pub fn start_server_and_talk() {
+    talk_to_client(start_server())
+}
+
+/// Starts an sbt server in the background and waits for it to be listening.
+///
+/// If one that `--client` started is still starting up, it's waited for instead.
+pub fn start_server() -> RunningServer {
+    timings::time("client", "start_server", start_and_await_server)
+}
+
+fn start_and_await_server() -> RunningServer {
+    let target = project::path("project/target");
+    fs::create_dir_all(&target).unwrap_or_else(|e| { die!("failed to create {}: {}", target.display(), e); });
+    let log_path = project::path(SERVER_LOG);
+    let log_name = log_path.display();
+    let mut child = None;
+    let pid = match server_pid().filter(|pid| server::is_alive(*pid)) {
+        Some(pid) => {
+            eprintln!("[info] waiting for the sbt server that's starting (pid {}), logging to {}", pid, log_name);
+            pid
+        },
+        None      => {
+            let log = File::create(&log_path).unwrap_or_else(|e| { die!("failed to create {}: {}", log_name, e); });
+            eprintln!("[info] starting an sbt server in the background, logging to {}", log_name);
+            let server = launcher::Launcher::from_args(project::ROOT.clone(), env::args().skip(1)).start_server(log).unwrap_or_else(|e| { die!("failed to start the sbt server: {}", e); });
+            let _ = fs::write(project::path(SERVER_PID), server.id().to_string());
+            let pid = server.id();
+            child = Some(server);
+            pid
+        },
+    };
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.as_mut().and_then(|child| child.try_wait().unwrap_or(None)) {
+            forget_server_pid();
+            die!("the sbt server exited ({}) before it was ready, see {}", status, log_name);
+        }
+        if child.is_none() && !server::is_alive(pid) {
+            forget_server_pid();
+            die!("the sbt server exited before it was ready, see {}", log_name);
+        }
+        if let Some(server) = try_connect() {
+            return server
+        }
+        if started.elapsed() > SERVER_START_TIMEOUT {
+            die!("the sbt server wasn't ready after {}s, see {}", SERVER_START_TIMEOUT.as_secs(), log_name);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Connects to the server in the port file, if it's been written and the server's listening.
+fn try_connect() -> Option<RunningServer> {
+    // the file might be there but not written in full yet, so read errors just mean "not yet"
+    let json: Value = serde_json::from_reader(File::open(project::path(PORT_FILE)).ok()?).ok()?;
+    let uri = ServerUri::parse(json["uri"].as_str()?).unwrap_or_else(|e| { die!("{}", e); });
+    let stream = Connection::connect(&uri).ok()?;
+    let token = json["tokenfilePath"].as_str().map(read_token);
+    Some(RunningServer { uri, stream, token })
+}
+
+fn talk_to_client_impl(stream: Connection, token: Option<String>) {
+    let args = ClientArgs::parse(env::args().skip(1)); // skip the path of the executable
+    let mut session = Session::connect(stream, token);
+    let human: Box<dyn Write> = if args.report_on_stdout() { Box::new(io::stderr()) } else { Box::new(io::stdout()) };
+    let colors = args.colors && if args.report_on_stdout() { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+    session.colors = colors;
+
+    let command_line = args.command_line.clone();
+    let continuous = watch::is_continuous(&command_line);
+
+    // attach for the IO of whatever the command runs, e.g. `run`, so it can be streamed and fed stdin,
+    // other than for a watch, where Enter is ours, to stop it
+    if session.features.terminal && session.attach() && !continuous {
+        let mut writer = MessageWriter::new(session.writer.get_ref().clone());
+        // a byte at a time, as that's how `sbt/systemIn` carries it
+        session.read_stdin(move |input| for &b in input {
+            let _ = writer.write_message(&notification("sbt/systemIn", &json!(b as i8)));
+        });
+    }
+
+    let exec_id = session.start_exec(&command_line);
+
+    let cancel_id = session.next_id();
+    if session.features.cancellation {
+        let interrupt = cancel_on_interrupt(session.writer.get_ref().clone(), exec_id, cancel_id, continuous);
+        if continuous {
+            let mut stopped = false;
+            session.read_stdin(move |input| if !stopped && input.contains(&b'\n') {
+                stopped = true;
+                let _ = interrupt.send(());
+            });
+        }
+    } else {
+        exit_on_interrupt();
+    }
+
+    let mut output = Output {
+          logger: Logger::new(colors, args.log_level),
+        renderer: Renderer::new(colors, env::current_dir().unwrap_or_default()),
+           watch: if continuous { Some(Watch::new(colors)) } else { None },
+           tests: if test_report::is_test_command(&command_line) { Some(TestReport::default()) } else { None },
+           human,
+    };
+    let exit_code = timings::time("client", "sbt/exec", || handle_msg_to_exit_code(&mut session, exec_id, cancel_id, &mut output));
+    session.close();
+
+    write_reports(&args, &mut output, &mut io::stdout());
+    timings::report();
+    match exit_code {
+        ExitCode::Cancelled if continuous => eprintln!("[info] stopped watching: {}", command_line),
+        ExitCode::Cancelled               => eprintln!("[warn] cancelled: {}", command_line),
+        _                                 => (),
+    }
+    exit(exit_code.status(continuous))
+}
+
+/// Summarises the diagnostics and tests, and writes any reports asked for, with the diagnostics
+/// report on `stdout` unless it's going to a file.
+fn write_reports(args: &ClientArgs, output: &mut Output, stdout: &mut dyn Write) {
+    if let Some(summary) = output.renderer.summary() {
+        let _ = writeln!(output.human, "{}", summary);
+    }
+    if let Some(tests) = output.tests.as_ref().filter(|tests| !tests.is_empty()) {
+        let _ = write!(output.human, "{}", tests.summary(output.logger.colors()));
+    }
+    match (&args.junit_xml, &output.tests) {
+        (Some(dir), Some(tests)) => if let Err(e) = tests.write_junit_xml(dir) {
+            eprintln!("[error] failed to write the JUnit XML reports to {}: {}", dir.display(), e);
+        },
+        (Some(_), None)          => eprintln!("[warn] --junit-xml only applies to commands that run tests, e.g. test or testOnly"),
+        (None, _)                => (),
+    }
+    if let Some(format) = args.diagnostics_format {
+        // relative to the root, not to wherever in the project we are, as that's where CI looks
+        let report = diagnostics::report(format, output.renderer.published(), &project::ROOT);
+        match &args.diagnostics_file {
+            Some(path) => if let Err(e) = fs::write(path, report) {
+                eprintln!("[error] failed to write the diagnostics to {}: {}", path.display(), e);
+            },
+            None       => { let _ = stdout.write_all(report.as_bytes()); },
+        }
+    }
+    let _ = output.human.flush();
+}
+
+/// `sbtl reload`: reloads the build in the running server, showing only what went wrong, if
+/// anything, e.g. an error in build.sbt.
+pub fn reload<I: Iterator<Item = String>>(mut args: I) {
+    let server = require_server(args.any(|arg| arg == "--client"));
+    let mut session = Session::connect(server.stream, server.token);
+    let colors = io::stdout().is_terminal();
+    session.colors = colors;
+
+    let exec_id = session.start_exec("reload");
+    let cancel_id = session.next_id();
+    if session.features.cancellation {
+        cancel_on_interrupt(session.writer.get_ref().clone(), exec_id, cancel_id, false);
+    } else {
+        exit_on_interrupt();
+    }
+
+    let mut output = Output {
+          logger: Logger::new(colors, log::Level::Error),
+        renderer: Renderer::new(colors, env::current_dir().unwrap_or_default()),
+           watch: None,
+           tests: None,
+           human: Box::new(io::stdout()),
+    };
+    let exit_code = timings::time("client", "sbt/exec", || handle_msg_to_exit_code(&mut session, exec_id, cancel_id, &mut output));
+    session.close();
+
+    timings::report();
+    match exit_code {
+        ExitCode::Success       => println!("[success] reloaded the build"),
+        ExitCode::Failure(code) => { eprintln!("[error] failed to reload the build"); exit(code) },
+        ExitCode::Cancelled     => { eprintln!("[warn] cancelled the reload"); exit(EXIT_INTERRUPTED) },
+    }
+}
+
+/// The running server, or else one started if `start`, for the subcommands that need one.
+pub fn require_server(start: bool) -> RunningServer {
+    match find_server() {
+        Some(server)  => server,
+        None if start => start_server(),
+        None          => {
+            eprintln!("[error] no sbt server is running, pass --client to start one");
+            exit(1)
+        },
+    }
+}
+
+/// Where what the server tells us ends up.
+struct Output {
+      logger: Logger,
+    renderer: Renderer,
+    /// Following the iterations of a continuous command, if it is one.
+       watch: Option<Watch>,
+    /// The results of the tests, if the command runs any, of its latest iteration for a watch.
+       tests: Option<TestReport>,
+    /// Where what's meant for people goes: stdout, unless that's taken by a diagnostics report.
+       human: Box<dyn Write>,
+}
+
+/// The arguments sbtl takes when there's an sbt server running to talk to.
+#[derive(Debug, PartialEq)]
+struct ClientArgs {
+          command_line: String,
+                colors: bool,
+             log_level: log::Level,
+    diagnostics_format: Option<diagnostics::Format>,
+      diagnostics_file: Option<PathBuf>,
+             junit_xml: Option<PathBuf>,
+}
+
+impl ClientArgs {
+    /// Whether stdout's for the machine-readable diagnostics report, leaving the rest to stderr.
+    fn report_on_stdout(&self) -> bool {
+        self.diagnostics_format.is_some() && self.diagnostics_file.is_none()
+    }
+
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> ClientArgs {
+        let mut command_line = None;
+        let mut colors = true;
+        let mut log_level = log::Level::Info;
+        let mut diagnostics_format = None;
+        let mut diagnostics_file = None;
+        let mut junit_xml = None;
+        while let Some(arg) = args.next() {
+            let mut require_arg = |tpe| match args.next() {
+                Some(arg) if !arg.is_empty() && !arg.starts_with('-') => arg,
+                _ => { die!("{opt} requires <{type}> argument", opt=arg, type=tpe); },
+            };
+            match arg.as_ref() {
+                "--diagnostics-format" => {
+                    let format = require_arg("format");
+                    diagnostics_format = Some(diagnostics::Format::parse(&format).unwrap_or_else(|| {
+                        die!("unknown diagnostics format '{}', expected json, sarif, github or checkstyle", format);
+                    }))
+                },
+                "--diagnostics-file"   => diagnostics_file = Some(PathBuf::from(require_arg("path"))),
+                "--junit-xml"          => junit_xml = Some(PathBuf::from(require_arg("dir"))),
+                "-timings"             => (), // see timings
+                "-timings-trace"       => { require_arg("path"); },
+                "-no-colors"           => colors = false,
+                "-d"                   => log_level = log::Level::Debug,
+                "-w"                   => log_level = log::Level::Warn,
+                "-q"                   => log_level = log::Level::Error,
+                // the launcher's, for when it starts a server
+                "--client" | "-v"      => (),
+                "-project-root"        => { require_arg("dir"); }, // see project::ROOT
+                "-connect-timeout" |
+                "-read-timeout"        => { require_arg("seconds"); }, // see CONNECT_TIMEOUT and READ_TIMEOUT
+                "-jvm-debug"           => { require_arg("port"); },
+                "-sbt-jar"             => { require_arg("path"); },
+                s if s.starts_with("-D") || s.starts_with("-J") => (),
+                _ if command_line.is_none() => command_line = Some(arg),
+                _                      => (),
+            }
+        }
+        ClientArgs {
+                  command_line: command_line.expect("at least one argument to sbt when server already running"),
+                        colors,
+                     log_level,
+            diagnostics_format,
+              diagnostics_file,
+                     junit_xml,
+        }
+    }
+}
+
+/// A session with an sbt server, reading its messages from `R` and writing ours to `W`.
+pub struct Session<R, W> {
+          reader: MessageReader<R>,
+          writer: MessageWriter<W>,
+         next_id: JsonRpcId,
+    capabilities: Value,
+    /// The version of sbt the server's running, if it told us.
+     sbt_version: Option<String>,
+        features: ServerFeatures,
+    /// Whether to tell the server the terminal supports colours.
+          colors: bool,
+    /// Where prompts get their input from, once stdin's been taken over by `read_stdin`.
+    prompt_input: Option<PromptInput>,
+}
+
+impl Session<BufReader<Heartbeat<Connection>>, SharedWriter> {
+    /// Starts an initialized session over a single connection.
+    pub fn connect(stream: Connection, token: Option<String>) -> Self {
+        let mut session = Session::open(stream);
+        timings::time("client", "initialize", || session.initialize(token));
+        session
+    }
+
+    /// Starts a session over a single connection, without initializing it, e.g. for BSP.
+    pub fn open(stream: Connection) -> Self {
+        let reading = stream.try_clone().unwrap();
+        reading.set_read_timeout(Some(HEARTBEAT)).unwrap();
+        let reader = BufReader::new(Heartbeat::new(reading, *READ_TIMEOUT));
+        Session::new(reader, SharedWriter::new(stream))
+    }
+
+    /// Ends the session by closing the connection.
+    ///
+    /// This deliberately doesn't send `shutdown`, as sbt takes that as a request to stop the server
+    /// itself, see `Session::shutdown`.
+    pub fn close(self) {
+        let _ = self.writer.get_ref().shutdown(Shutdown::Both);
+    }
+
+    /// Performs the `shutdown` request and `exit` notification, which stops the sbt server.
+    pub fn shutdown(mut self) -> Result<(), jsonrpc_lite::Error> {
+        let id = self.send_request("shutdown", &Value::Null);
+        self.await_response(id)?;
+        self.send_notification("exit", &Value::Null);
+        self.close();
+        Ok(())
+    }
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Session {
+                  reader: MessageReader::new(reader),
+                  writer: MessageWriter::new(writer),
+                 next_id: 1,
+            capabilities: Value::Null,
+             sbt_version: None,
+                features: ServerFeatures::default(),
+                  colors: false,
+            prompt_input: None,
+        }
+    }
+
+    /// Performs the `initialize` handshake, followed by the `initialized` notification, then asks
+    /// the server which sbt it's running, to know what it supports beyond its capabilities.
+    fn initialize(&mut self, token: Option<String>) {
+        let id = self.send_request("initialize", &sbt_client::initialize_params(token.as_deref(), &project::ROOT));
+        let result = self.await_response(id).unwrap_or_else(|e| { die!("failed to initialize the sbt server session: {}", e); });
+        self.capabilities = result["capabilities"].clone();
+        self.send_notification("initialized", &json!({}));
+
+        let id = self.send_request("sbt/setting", &sbt_client::setting_params("sbtVersion"));
+        self.sbt_version = self.await_response(id).ok().and_then(|result| result["value"].as_str().map(str::to_owned));
+        // servers before sbt 1.1 can't say, and are old anyway, but the configured version's the best guess
+        let sbt_version = self.sbt_version.clone().unwrap_or_else(launcher::build_props_sbt);
+        self.features = ServerFeatures::new(&self.capabilities, &sbt_version);
+    }
+
+    /// The version of sbt the server's running, if it told us when the session was initialized.
+    pub fn sbt_version(&self) -> Option<&str> { self.sbt_version.as_deref() }
+
+    pub(crate) fn features(&self) -> &ServerFeatures { &self.features }
+
+    /// Asks the server to forward the IO of the commands we run to us, returning whether it will.
+    fn attach(&mut self) -> bool {
+        let id = self.send_request("sbt/attach", &json!({"interactive": false}));
+        self.await_response(id).is_ok()
+    }
+
+    /// Reads stdin on a thread of its own, passing what's typed to `sink`, other than while a
+    /// `window/showMessageRequest` prompts the user, when it's the answer to that; there being one
+    /// stdin, this is its only reader from then on.
+    fn read_stdin<F: FnMut(&[u8]) + Send + 'static>(&mut self, mut sink: F) {
+        let prompt_input = PromptInput::default();
+        self.prompt_input = Some(prompt_input.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            loop {
+                match stdin.read(&mut buf) {
+                    Ok(0) | Err(_)                        => return prompt_input.close(),
+                    Ok(n) if prompt_input.give(&buf[..n]) => (), // the answer to a prompt
+                    Ok(n)                                 => sink(&buf[..n]),
+                }
+            }
+        });
+    }
+
+    /// Starts running a command, returning the id of the `sbt/exec` request.
+    pub fn start_exec(&mut self, command_line: &str) -> JsonRpcId {
+        self.send_request("sbt/exec", &sbt_client::exec_params(command_line))
+    }
+
+    fn next_id(&mut self) -> JsonRpcId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn send_request(&mut self, method: &str, params: &Value) -> JsonRpcId {
+        let id = self.next_id();
+        self.write(&request(id, method, params));
+        id
+    }
+
+    pub fn send_notification(&mut self, method: &str, params: &Value) {
+        self.write(&notification(method, params));
+    }
+
+    fn write(&mut self, msg: &Value) {
+        self.writer.write_message(msg).unwrap_or_else(|e| { die!("failed to send a message to the sbt server: {}", e); });
+    }
+
+    /// Reads messages until the response to the request with the given id, quietly skipping any
+    /// notifications the server sends in the meantime.
+    pub fn await_response(&mut self, id: JsonRpcId) -> Result<Value, jsonrpc_lite::Error> {
+        self.await_response_with(id, |_, _| ())
+    }
+
+    /// Reads messages until the response to the request with the given id, passing the method and
+    /// params of any notifications the server sends in the meantime to `on_notification`.
+    pub fn await_response_with<F: FnMut(&str, Value)>(&mut self, id: JsonRpcId, mut on_notification: F) -> Result<Value, jsonrpc_lite::Error> {
+        loop {
+            let json_rpc = read_json_rpc(&mut self.reader);
+            let is_response = json_rpc.get_id().map(id_to_string) == Some(id.to_string());
+            match json_rpc {
+                JsonRpc::Success(_) if is_response => return Ok(json_rpc.get_result().cloned().unwrap_or(Value::Null)),
+                JsonRpc::Error(_)   if is_response => return Err(json_rpc.get_error().cloned().unwrap()),
+                JsonRpc::Request(_)                => self.handle_request(&json_rpc),
+                JsonRpc::Notification(_)           => on_notification(json_rpc.get_method().unwrap_or(""), params_of(&json_rpc)),
+                _                                  => (),
+            }
+        }
+    }
+
+    /// Responds to a request from the server: the ones we understand with their result,
+    /// the rest with a `MethodNotFound` error, so the server isn't left waiting.
+    fn handle_request(&mut self, request: &JsonRpc) {
+        let id = request.get_id().map(id_to_json).unwrap_or(Value::Null);
+        let params = params_of(request);
+        let response = match request.get_method() {
+            Some("window/showMessageRequest") => {
+                let stdin = io::stdin();
+                let interactive = stdin.is_terminal();
+                let result = match &self.prompt_input {
+                    Some(input) => show_message_request(&params, interactive, &mut BufReader::new(input.open()), &mut io::stderr()),
+                    None        => show_message_request(&params, interactive, &mut stdin.lock(), &mut io::stderr()),
+                };
+                json!({"jsonrpc": "2.0", "id": id, "result": result})
+            },
+            Some("sbt/terminalPropertiesQuery") => {
+                let dimension = |var, default| env::var(var).ok().and_then(|v| v.parse::<u32>().ok()).unwrap_or(default);
+                json!({"jsonrpc": "2.0", "id": id, "result": {
+                    "width": dimension("COLUMNS", 80),
+                    "height": dimension("LINES", 24),
+                    "isAnsiSupported": self.colors,
+                    "isColorEnabled": self.colors,
+                    "isSupershellEnabled": false,
+                    "isEchoEnabled": true,
+                }})
+            },
+            Some("sbt/terminalCapabilities")    => {
+                // we don't have a terminfo database to hand, so we don't know any of them
+                json!({"jsonrpc": "2.0", "id": id, "result": {"boolean": null, "numeric": null, "string": null}})
+            },
+            Some("workspace/configuration")   => {
+                // we've no configuration of our own, so every item is unset
+                let items = params["items"].as_array().map(Vec::len).unwrap_or(0);
+                json!({"jsonrpc": "2.0", "id": id, "result": vec![Value::Null; items]})
+            },
+            method                            => sbt_client::method_not_found(&id, method.unwrap_or("requests without a method")),
+        };
+        self.write(&response);
     }
 }
 
-pub fn talk_to_client(port_file: File) {
-    let json: serde_json::Value = serde_json::from_reader(port_file).unwrap();
-    let uri = json["uri"].as_str().unwrap();
-    // TODO: Use a less idiotic way to get the path from the URI
-    let socket_file_path = &uri[8..];
+/// Shows the message of a `window/showMessageRequest` and, when we can prompt the user, lets them
+/// pick one of its actions, which is the result of the request (`null` if they don't pick one).
+fn show_message_request<I: BufRead, O: Write>(params: &Value, interactive: bool, input: &mut I, output: &mut O) -> Value {
+    let message = params["message"].as_str().unwrap_or("");
+    let level = log::Level::from_lsp(params["type"].as_i64().unwrap_or(3), message);
+    let actions = params["actions"].as_array().cloned().unwrap_or_default();
+    let _ = writeln!(output, "[{}] {}", level.label(), message);
+    if !interactive || actions.is_empty() { return Value::Null }
 
-    match UnixStream::connect(socket_file_path) {
-        Ok(stream) => talk_to_client_impl(socket_file_path, stream),
-        Err(_)     => {
-            fs::remove_file("project/target/active.json").expect("Failed to delete port file");
-            crate::main()
+    for (i, action) in actions.iter().enumerate() {
+        let _ = writeln!(output, "  {}) {}", i + 1, action["title"].as_str().unwrap_or(""));
+    }
+    let _ = write!(output, "Choose an action [1-{}]: ", actions.len());
+    let _ = output.flush();
+
+    let mut answer = String::new();
+    match input.read_line(&mut answer) {
+        Ok(n) if n > 0 => match answer.trim().parse::<usize>() {
+            Ok(i) if i >= 1 && i <= actions.len() => actions[i - 1].clone(),
+            _                                     => Value::Null,
         },
+        _              => Value::Null,
+    }
+}
+
+/// Writes the raw bytes of an `sbt/systemOut` or `sbt/systemErr` notification, so partial lines
+/// and escape sequences come out exactly as the running program wrote them.
+fn forward_output<O: Write>(out: &mut O, params: &Value) {
+    let _ = out.write_all(&output_bytes(params));
+    let _ = out.flush();
+}
+
+fn output_bytes(params: &Value) -> Vec<u8> {
+    match params.as_array() {
+        Some(bytes) => bytes.iter().filter_map(Value::as_i64).map(|b| b as u8).collect(), // bytes are signed on the JVM
+        None        => Vec::new(),
+    }
+}
+
+/// Where what's typed goes while a prompt's open, when stdin's been taken over by
+/// `Session::read_stdin`: as it's read on another thread, the prompt can't read it itself.
+#[derive(Clone, Default)]
+struct PromptInput(Arc<Mutex<PromptState>>);
+
+#[derive(Default)]
+struct PromptState {
+      open: Option<mpsc::Sender<Vec<u8>>>,
+    /// Whether stdin's been closed, so there's nothing more for any prompt.
+    closed: bool,
+}
+
+impl PromptInput {
+    /// Passes the input to the open prompt, if there is one, returning whether there was.
+    fn give(&self, input: &[u8]) -> bool {
+        match &self.0.lock().unwrap().open {
+            Some(prompt) => prompt.send(input.to_vec()).is_ok(),
+            None         => false,
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.open = None;
+        state.closed = true;
+    }
+
+    /// Opens a prompt, which gets what's typed until it's dropped.
+    fn open(&self) -> Prompt {
+        let (tx, rx) = mpsc::channel();
+        let mut state = self.0.lock().unwrap();
+        if !state.closed { state.open = Some(tx) }
+        Prompt { input: self.clone(), rx, pending: Vec::new() }
+    }
+}
+
+struct Prompt {
+      input: PromptInput,
+         rx: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Read for Prompt {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv() {
+                Ok(input) => self.pending = input,
+                Err(_)    => return Ok(0), // stdin's closed
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
     }
 }
 
-fn talk_to_client_impl<P: AsRef<Path>>(socket_file_path: P, mut stream: UnixStream) {
-    let json_str = make_lsp_json_str(1, "initialize", &json!({})).unwrap();
-    stream.write_all(json_str.as_bytes()).unwrap();
-    stream.flush().unwrap();
+impl Drop for Prompt {
+    fn drop(&mut self) { self.input.0.lock().unwrap().open = None }
+}
+
+/// Installs a Ctrl-C handler for servers that can't cancel requests, which lets the user know that
+/// the command carries on running in the server.
+fn exit_on_interrupt() {
+    ctrlc::set_handler(|| {
+        eprintln!("[warn] this sbt server doesn't support cancellation, the command will carry on running in it");
+        exit(EXIT_INTERRUPTED)
+    }).expect("failed to set the Ctrl-C handler");
+}
+
+/// Installs a Ctrl-C handler that asks the server to cancel the request with the given id,
+/// returning a sender to interrupt it the same way with, e.g. on Enter for a watch (`~compile`),
+/// as in sbt.
+///
+/// The response to the cancellation is picked up by `handle_msg_to_exit_code`; if it doesn't
+/// arrive within `CANCEL_ACK_TIMEOUT`, or the user hits Ctrl-C a second time, we exit straight away,
+/// with the same status as if it had (so 0 for a watch).
+fn cancel_on_interrupt(writer: SharedWriter, id: JsonRpcId, cancel_id: JsonRpcId, continuous: bool) -> mpsc::Sender<()> {
+    let (tx, rx) = mpsc::channel();
+    let interrupted = tx.clone();
+    ctrlc::set_handler(move || { let _ = interrupted.send(()); }).expect("failed to set the Ctrl-C handler");
 
-    let mut reader = BufReader::new(stream);
-    handle_msg_quietly(&mut reader);
+    thread::spawn(move || {
+        if rx.recv().is_err() { return }
+        if continuous {
+            eprintln!("[info] stopping the watch, press Ctrl-C again to exit immediately");
+        } else {
+            eprintln!("[warn] cancelling, press Ctrl-C again to exit immediately");
+        }
+        if cancel(writer, id, cancel_id, &rx, CANCEL_ACK_TIMEOUT) == Cancellation::Unacknowledged {
+            eprintln!("[warn] the server did not acknowledge the cancellation, exiting anyway");
+        }
+        exit(ExitCode::Cancelled.status(continuous))
+    });
+    tx
+}
 
-    let mut args = env::args().skip(1); // skip the path of the executable
-    let command_line = args.nth(0).expect("at least one argument to sbt when server already running");
-    let json_str2 = make_lsp_json_str(2, "sbt/exec", &json!({"commandLine": command_line})).unwrap();
-    let mut stream2 = UnixStream::connect(socket_file_path).unwrap();
-    stream2.write_all(json_str2.as_bytes()).unwrap();
-    stream2.flush().unwrap();
+/// How far asking the server to cancel a request got, short of it acknowledging the cancellation,
+/// which ends the command on the main thread.
+#[derive(Debug, PartialEq)]
+enum Cancellation {
+    /// The server's connection was already gone.
+    Unsent,
+    /// The user hit Ctrl-C again, not wanting to wait.
+    Forced,
+    /// Nothing came of it within the timeout.
+    Unacknowledged,
+}
 
-    let reader2 = BufReader::new(stream2);
-    match handle_msg_to_exit_code(reader2, 2) {
-        ExitCode::Failure => exit(1),
-        ExitCode::Success => exit(0),
+/// Sends `sbt/cancelRequest` for the request with the given id, then waits up to `timeout` for the
+/// main thread to exit on the acknowledgement, unless `interrupts` says the user's insisting.
+fn cancel<W: Write>(writer: W, id: JsonRpcId, cancel_id: JsonRpcId, interrupts: &mpsc::Receiver<()>, timeout: Duration) -> Cancellation {
+    let cancel = sbt_client::cancel_request(cancel_id, id);
+    if MessageWriter::new(writer).write_message(&cancel).is_err() { return Cancellation::Unsent }
+    match interrupts.recv_timeout(timeout) {
+        Err(mpsc::RecvTimeoutError::Timeout) => Cancellation::Unacknowledged,
+        _                                    => Cancellation::Forced,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use jsonrpc_lite::JsonRpc;
-    use serde_json;
     use super::*;
 
+    #[test]
+    fn parse_local_uri() {
+        assert_eq!(ServerUri::parse("local:///tmp/sbt.sock").unwrap().to_string(), "local:///tmp/sbt.sock");
+        assert_eq!(ServerUri::parse("local:///home/me/.sbt/1.0/server/0845deda/sock"),
+                   Ok(ServerUri::Local(PathBuf::from("/home/me/.sbt/1.0/server/0845deda/sock"))));
+    }
+
+    #[test]
+    fn parse_tcp_uri() {
+        assert_eq!(ServerUri::parse("tcp://127.0.0.1:5010"), Ok(ServerUri::Tcp("127.0.0.1:5010".to_owned())));
+        assert_eq!(ServerUri::parse("tcp://localhost:5010/"), Ok(ServerUri::Tcp("localhost:5010".to_owned())));
+    }
+
+    #[test]
+    fn parse_bad_uris() {
+        assert!(ServerUri::parse("local:sbt-server-0845deda").is_err());
+        assert!(ServerUri::parse("local://").is_err());
+        assert!(ServerUri::parse("tcp://127.0.0.1").is_err());
+        assert!(ServerUri::parse("tcp://:5010").is_err());
+        assert!(ServerUri::parse("http://127.0.0.1:5010").is_err());
+        assert!(ServerUri::parse("/tmp/sock").is_err());
+    }
+
+    #[test]
+    fn features_from_the_sbt_version() {
+        let all = ServerFeatures { completion: true, cancellation: true, bsp: true, exit_code: true, terminal: true };
+        assert_eq!(ServerFeatures::new(&json!({}), "1.4.0"), all);
+        assert_eq!(ServerFeatures::new(&Value::Null, "1.9.7"), all);
+        assert_eq!(ServerFeatures::new(&json!({}), "1.3.13"), ServerFeatures::default());
+        assert_eq!(ServerFeatures::new(&json!({}), ""), ServerFeatures::default());
+    }
+
+    #[test]
+    fn features_from_capabilities() {
+        let capabilities = json!({"completionProvider": {"triggerCharacters": []}, "bspProvider": false});
+        let features = ServerFeatures { completion: true, cancellation: true, bsp: false, exit_code: true, terminal: true };
+        assert_eq!(ServerFeatures::new(&capabilities, "1.4.0"), features);
+        let features = ServerFeatures { completion: true, cancellation: false, bsp: false, exit_code: false, terminal: false };
+        assert_eq!(ServerFeatures::new(&capabilities, "1.2.8"), features);
+    }
+
+    #[test]
+    fn features_from_the_running_sbt() {
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "result": {"capabilities": {}}});
+        let mut session = session_reading(&[initialize.clone(), json!({"jsonrpc": "2.0", "id": 2, "result": {"value": "1.3.13"}})], ServerFeatures::default());
+        session.initialize(None);
+        assert_eq!(session.sbt_version(), Some("1.3.13"));
+        assert_eq!(session.features, ServerFeatures::default());
+        assert_eq!(sent(session)[2], request(2, "sbt/setting", &json!({"setting": "sbtVersion"})));
+
+        let mut session = session_reading(&[initialize, json!({"jsonrpc": "2.0", "id": 2, "result": {"value": "1.9.6"}})], ServerFeatures::default());
+        session.initialize(None);
+        assert!(session.features.completion && session.features.cancellation && session.features.terminal);
+    }
+
+    #[test]
+    fn root_uri() {
+        assert_eq!(file_uri(Path::new("/home/me/my project")), "file:///home/me/my%20project");
+    }
+
+    /// A session that reads the given messages from the server.
+    fn session_reading(msgs: &[Value], features: ServerFeatures) -> Session<io::Cursor<Vec<u8>>, Vec<u8>> {
+        let mut writer = MessageWriter::new(Vec::new());
+        for msg in msgs {
+            writer.write_message(msg).unwrap();
+        }
+        let mut session = Session::new(io::Cursor::new(writer.get_ref().clone()), Vec::new());
+        session.features = features;
+        session
+    }
+
+    /// The messages the session sent to the server.
+    fn sent(session: Session<io::Cursor<Vec<u8>>, Vec<u8>>) -> Vec<Value> {
+        let mut reader = MessageReader::new(&session.writer.get_ref()[..]);
+        let mut msgs = Vec::new();
+        while let Some(msg) = reader.read_message().unwrap() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    fn output() -> Output {
+        Output {
+              logger: Logger::new(false, log::Level::Info),
+            renderer: Renderer::new(false, PathBuf::new()),
+               watch: None,
+               tests: None,
+               human: Box::new(io::sink()),
+        }
+    }
+
+    fn log_message(lvl: i64, msg: &str) -> Value {
+        json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": lvl, "message": msg}})
+    }
+
+    #[test]
+    fn exit_code_from_exec_result() {
+        let modern = ServerFeatures::new(&json!({}), "1.4.0");
+        let mut session = session_reading(&[
+            log_message(1, "Nonzero exit code: 3"),
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 3}}),
+        ], modern.clone());
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Failure(3));
+
+        let mut session = session_reading(&[json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 0}})], modern);
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Success);
+    }
+
+    #[test]
+    fn exit_code_from_exec_error() {
+        let modern = ServerFeatures::new(&json!({}), "1.4.0");
+        let mut session = session_reading(&[
+            json!({"jsonrpc": "2.0", "id": 2, "error": {"code": -33000, "message": "failed", "data": {"exitCode": 2}}}),
+        ], modern);
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Failure(2));
+    }
+
+    #[test]
+    fn exit_code_from_old_server_logs() {
+        let old = ServerFeatures::new(&json!({}), "1.2.8");
+        let mut session = session_reading(&[log_message(4, "Exited with code 0"), log_message(4, "Done")], old.clone());
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Success);
+
+        let mut session = session_reading(&[log_message(1, "Compilation failed")], old);
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Failure(1));
+    }
+
+    #[test]
+    fn exit_code_when_cancelled() {
+        let modern = ServerFeatures::new(&json!({}), "1.4.0");
+        let mut session = session_reading(&[
+            log_message(3, "compiling"),
+            json!({"jsonrpc": "2.0", "id": 2, "error": {"code": REQUEST_CANCELLED, "message": "Task cancelled"}}),
+        ], modern.clone());
+        let exit_code = handle_msg_to_exit_code(&mut session, 2, 3, &mut output());
+        assert_eq!(exit_code, ExitCode::Cancelled);
+        assert_eq!(exit_code.status(false), 130);
+        assert_eq!(exit_code.status(true), 0); // a watch that's been stopped
+
+        // sbt acknowledging the cancellation before the request's error
+        let mut session = session_reading(&[json!({"jsonrpc": "2.0", "id": 3, "result": {"status": "Task cancelled"}})], modern);
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Cancelled);
+        assert_eq!(ExitCode::Failure(2).status(false), 2);
+    }
+
+    #[test]
+    fn cancellation() {
+        let (interrupt, interrupts) = mpsc::channel();
+        let mut sent = Vec::new();
+        let started = Instant::now();
+        assert_eq!(cancel(&mut sent, 2, 3, &interrupts, Duration::from_millis(50)), Cancellation::Unacknowledged);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let cancel_request = MessageReader::new(&sent[..]).read_message().unwrap().unwrap();
+        assert_eq!(cancel_request, request(3, "sbt/cancelRequest", &json!({"id": "2"})));
+
+        interrupt.send(()).unwrap(); // Ctrl-C again
+        assert_eq!(cancel(&mut Vec::new(), 2, 3, &interrupts, Duration::from_secs(60)), Cancellation::Forced);
+
+        let (_stream, closed) = UnixStream::pair().unwrap();
+        closed.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(cancel(closed, 2, 3, &interrupts, Duration::from_secs(60)), Cancellation::Unsent);
+    }
+
+    #[test]
+    fn skip_malformed_log_messages() {
+        let mut session = session_reading(&[
+            json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": ["compiling"]}),
+            json!({"jsonrpc": "2.0", "method": "window/logMessage"}),
+            json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": "info", "message": "compiling"}}),
+            json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": 3}}),
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 0}}),
+        ], ServerFeatures::new(&json!({}), "1.4.0"));
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Success);
+    }
+
+    #[test]
+    fn respond_to_server_requests() {
+        let modern = ServerFeatures::new(&json!({}), "1.4.0");
+        let mut session = session_reading(&[
+            json!({"jsonrpc": "2.0", "id": "a", "method": "workspace/configuration", "params": {"items": [{"section": "sbt"}, {}]}}),
+            json!({"jsonrpc": "2.0", "id": 7, "method": "workspace/applyEdit", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 0}}),
+        ], modern);
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Success);
+        let sent = sent(session);
+        assert_eq!(sent[0], json!({"jsonrpc": "2.0", "id": "a", "result": [null, null]}));
+        assert_eq!(sent[1]["id"], 7);
+        assert_eq!(sent[1]["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn answer_terminal_queries() {
+        let mut session = session_reading(&[
+            json!({"jsonrpc": "2.0", "id": 5, "method": "sbt/terminalPropertiesQuery"}),
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 0}}),
+        ], ServerFeatures::new(&json!({}), "1.4.0"));
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output()), ExitCode::Success);
+        let sent = sent(session);
+        assert_eq!(sent[0]["id"], 5);
+        assert_eq!(sent[0]["result"]["isColorEnabled"], false);
+        assert!(sent[0]["result"]["width"].is_u64());
+    }
+
+    #[test]
+    fn system_out_bytes() {
+        assert_eq!(output_bytes(&json!([104, 105, 27, -61, -87])), b"hi\x1b\xc3\xa9".to_vec());
+        assert_eq!(output_bytes(&Value::Null), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn prompt_for_show_message_request() {
+        let params = json!({"type": 2, "message": "Reload the build?", "actions": [{"title": "Yes"}, {"title": "No"}]});
+        let mut output = Vec::new();
+        assert_eq!(show_message_request(&params, true, &mut &b"2\n"[..], &mut output), json!({"title": "No"}));
+        assert_eq!(String::from_utf8(output).unwrap(), "[warn] Reload the build?\n  1) Yes\n  2) No\nChoose an action [1-2]: ");
+
+        assert_eq!(show_message_request(&params, true, &mut &b"3\n"[..], &mut Vec::new()), Value::Null);
+        assert_eq!(show_message_request(&params, true, &mut &b""[..], &mut Vec::new()), Value::Null);
+        assert_eq!(show_message_request(&params, false, &mut &b"1\n"[..], &mut Vec::new()), Value::Null);
+    }
+
+    #[test]
+    fn prompt_while_reading_stdin() {
+        let params = json!({"type": 3, "message": "Reload the build?", "actions": [{"title": "Yes"}, {"title": "No"}]});
+        let input = PromptInput::default();
+        assert!(!input.give(b"for the program\n"));
+
+        let prompt = input.open();
+        let typing = input.clone();
+        let typist = thread::spawn(move || {
+            while !typing.give(b"1") { thread::sleep(Duration::from_millis(10)) }
+            assert!(typing.give(b"\n"));
+        });
+        assert_eq!(show_message_request(&params, true, &mut BufReader::new(prompt), &mut Vec::new()), json!({"title": "Yes"}));
+        typist.join().unwrap();
+        assert!(!input.give(b"for the program again\n")); // the prompt's closed
+
+        input.close();
+        assert_eq!(show_message_request(&params, true, &mut BufReader::new(input.open()), &mut Vec::new()), Value::Null);
+    }
+
+    /// Output written to a buffer that's shared, so it can be read after it's been written.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn text(&self) -> String { String::from_utf8(self.0.lock().unwrap().clone()).unwrap() }
+    }
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn diagnostics_report_on_stdout() {
+        for format in &["json", "sarif"] {
+            let args = client_args(&["--diagnostics-format", format, "compile"]);
+            assert!(args.report_on_stdout());
+            let human = Captured::default();
+            let mut output = Output { human: Box::new(human.clone()), ..output() };
+            let mut session = session_reading(&[
+                log_message(3, "compiling 1 Scala source"),
+                json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {"uri": "file:///nonexistent/Foo.scala", "diagnostics": [
+                    {"range": {"start": {"line": 1, "character": 2}, "end": {"line": 1, "character": 3}}, "severity": 1, "message": "type mismatch"},
+                ]}}),
+                log_message(1, "Compilation failed"),
+                json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 1}}),
+            ], ServerFeatures::new(&json!({}), "1.4.0"));
+            assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output), ExitCode::Failure(1));
+            let mut stdout = Vec::new();
+            write_reports(&args, &mut output, &mut stdout);
+
+            let report: Value = serde_json::from_slice(&stdout).unwrap(); // nothing but the report
+            let message = if *format == "json" { &report[0]["message"] } else { &report["runs"][0]["results"][0]["message"]["text"] };
+            assert_eq!(message, "type mismatch");
+            let human = human.text();
+            assert!(human.contains("[info] compiling 1 Scala source\n"));
+            assert!(human.contains("/nonexistent/Foo.scala:2:3: error: type mismatch\n"));
+            assert!(human.ends_with("1 error found\n"));
+        }
+        assert!(!client_args(&["--diagnostics-format", "json", "--diagnostics-file", "d.json", "compile"]).report_on_stdout());
+    }
+
+    #[test]
+    fn diagnostics_report_relative_to_the_root() {
+        let args = client_args(&["--diagnostics-format", "json", "compile"]);
+        let mut output = Output { renderer: Renderer::new(false, project::path("src")), ..output() }; // as if run from src
+        output.renderer.render(&FileDiagnostics::from_params(&json!({"uri": file_uri(&project::path("src/Foo.scala")), "diagnostics": [
+            {"range": {"start": {"line": 1, "character": 2}, "end": {"line": 1, "character": 3}}, "severity": 1, "message": "type mismatch"},
+        ]})).unwrap());
+        let mut stdout = Vec::new();
+        write_reports(&args, &mut output, &mut stdout);
+        let report: Value = serde_json::from_slice(&stdout).unwrap();
+        assert_eq!(report[0]["file"], "src/Foo.scala");
+    }
+
+    #[test]
+    fn tests_of_the_latest_iteration() {
+        let mut watching = Output { watch: Some(Watch::new(false)), tests: Some(TestReport::default()), ..output() };
+        let mut session = session_reading(&[
+            log_message(3, "BarSpec:\n- adds\n- subtracts *** FAILED ***"),
+            log_message(3, "Total time: 1 s"),
+            log_message(3, "Build triggered by /p/src/test/scala/BarSpec.scala. Running 'test'."),
+            log_message(3, "BarSpec:\n- adds"),
+            json!({"jsonrpc": "2.0", "id": 3, "result": {}}), // stopped
+        ], ServerFeatures::new(&json!({}), "1.4.0"));
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut watching), ExitCode::Cancelled);
+        let tests = watching.tests.unwrap();
+        assert_eq!(tests.suites.len(), 1);
+        assert_eq!(tests.suites[0].cases.len(), 1);
+
+        let mut output = output(); // not a test command, e.g. `run`, whose output just looks like tests
+        let mut session = session_reading(&[
+            log_message(3, "BarSpec:\n- adds"),
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"status": "Done", "exitCode": 0}}),
+        ], ServerFeatures::new(&json!({}), "1.4.0"));
+        assert_eq!(handle_msg_to_exit_code(&mut session, 2, 3, &mut output), ExitCode::Success);
+        assert!(output.tests.is_none());
+    }
+
+    fn client_args(args: &[&str]) -> ClientArgs {
+        ClientArgs::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    /// A reader that times out a number of times before it has something to read.
+    struct Slow { timeouts: usize, data: &'static [u8] }
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.timeouts > 0 {
+                self.timeouts -= 1;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut buf = [0; 8];
+        let mut patient = Heartbeat::new(Slow { timeouts: 3, data: b"hi" }, None);
+        assert_eq!(patient.read(&mut buf).unwrap(), 2);
+
+        let mut impatient = Heartbeat::new(Slow { timeouts: 3, data: b"hi" }, Some(Duration::from_secs(0)));
+        assert_eq!(impatient.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn server_gone_mid_command() {
+        let mut session = session_reading(&[], ServerFeatures::default());
+        assert!(matches!(session.reader.read_message(), Ok(None))); // which read_json_rpc exits on, with EXIT_SERVER_LOST
+        assert!(server_lost(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(!server_lost(&io::Error::from(io::ErrorKind::TimedOut)));
+    }
+
+    #[test]
+    fn connect_timeout() {
+        use std::os::unix::io::AsRawFd;
+        let socket = env::temp_dir().join(format!("sbtl-backlog-{}.sock", process::id()));
+        let _ = fs::remove_file(&socket);
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        unsafe { libc::listen(listener.as_raw_fd(), 0) }; // never accepting, so its backlog's soon full
+        let uri = ServerUri::Local(socket.clone());
+        let timeout = Duration::from_millis(200);
+        let mut queued = Vec::new();
+        let e = loop {
+            let started = Instant::now();
+            match Connection::connect_within(&uri, timeout) {
+                Ok(stream) => queued.push(stream),
+                Err(e)     => { assert!(started.elapsed() < timeout + Duration::from_secs(1)); break e },
+            }
+            assert!(queued.len() < 10, "the connections were all accepted");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        fs::remove_file(&socket).unwrap();
+
+        let uri = ServerUri::Local(PathBuf::from("/nonexistent/sbtl.sock"));
+        assert_eq!(Connection::connect_within(&uri, Duration::from_secs(5)).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn stale_servers() {
+        let dir = env::temp_dir().join(format!("sbtl-stale-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("sock");
+        let uri = ServerUri::Local(socket.clone());
+        let timeout = Duration::from_secs(5);
+        assert!(matches!(liveness(&uri, None, None, timeout), Liveness::Stale));
+        assert!(matches!(liveness(&uri, None, Some(process::id()), timeout), Liveness::Unknown(_))); // its process is still alive
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let initialize = MessageReader::new(BufReader::new(stream.try_clone().unwrap())).read_message().unwrap().unwrap();
+            assert_eq!(initialize["method"], "initialize");
+            let response = json!({"jsonrpc": "2.0", "id": initialize["id"], "result": {"capabilities": {}}});
+            MessageWriter::new(stream).write_message(&response).unwrap();
+            listener
+        });
+        assert!(matches!(liveness(&uri, None, None, timeout), Liveness::Alive(_)));
+
+        let listener = server.join().unwrap(); // which now accepts connections without ever answering them
+        match liveness(&uri, None, None, Duration::from_millis(100)) {
+            Liveness::Unknown(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            _                    => panic!("a wedged server isn't alive"),
+        }
+        drop(listener); // leaves the socket file behind, with nothing listening on it
+        assert!(matches!(liveness(&uri, None, None, timeout), Liveness::Stale));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn unix_socket_peer_pid() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert_eq!(Connection::Unix(a).peer_pid(), Some(process::id()));
+    }
+
+    #[test]
+    fn parse_client_args() {
+        assert_eq!(client_args(&["compile"]), ClientArgs {
+            command_line: "compile".to_owned(), colors: true, log_level: log::Level::Info, diagnostics_format: None, diagnostics_file: None,
+            junit_xml: None,
+        });
+        assert_eq!(client_args(&["--diagnostics-format", "sarif", "compile", "-no-colors", "--diagnostics-file", "target/scalac.sarif", "-w", "--junit-xml", "target/junit"]), ClientArgs {
+                  command_line: "compile".to_owned(),
+                        colors: false,
+                     log_level: log::Level::Warn,
+            diagnostics_format: Some(diagnostics::Format::Sarif),
+              diagnostics_file: Some(PathBuf::from("target/scalac.sarif")),
+                     junit_xml: Some(PathBuf::from("target/junit")),
+        });
+        assert_eq!(client_args(&["--client", "-v", "-Dfoo=bar", "-sbt-jar", "sbt-launch.jar", "test"]).command_line, "test");
+    }
+
     #[test]
     fn decode_ok() {
         let msg = r#"{"jsonrpc":"2.0","result":{"commandQueue":["collectAnalyses","shell"],"exitCode":0,"status":"Done"},"id":null}"#;
-        let json = serde_json::from_str::<serde_json::Value>(&msg).unwrap();
+        let json = serde_json::from_str::<serde_json::Value>(msg).unwrap();
         let json_rpc: JsonRpc = serde_json::from_value(json).expect("is JSON RPC");
         drop(json_rpc)
     }
@@ -249,7 +1808,7 @@ mod tests {
     #[should_panic(expected = "data did not match any variant of untagged enum JsonRpc")]
     fn decode_ko() {
         let msg = r#"{"jsonrpc":"2.0","result":{"commandQueue":["collectAnalyses","shell"],"exitCode":0,"status":"Done"}}"#;
-        let json = serde_json::from_str::<serde_json::Value>(&msg).unwrap();
+        let json = serde_json::from_str::<serde_json::Value>(msg).unwrap();
         let json_rpc: JsonRpc = serde_json::from_value(json).expect("is JSON RPC");
         drop(json_rpc)
     }
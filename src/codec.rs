@@ -0,0 +1,330 @@
+//! The base protocol framing of the Language Server Protocol, which sbt's server speaks:
+//! a few `Name: value` headers, an empty line, then `Content-Length` bytes of JSON.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use serde_json::Value;
+
+/// The largest message body we're willing to buffer, so a corrupt or hostile `Content-Length`
+/// can't make us allocate without bound.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The longest header line we're willing to read, for the same reason.
+const MAX_HEADER_LINE: u64 = 8 * 1024;
+
+const HEADER_CONTENT_LENGTH: &str = "content-length";
+const HEADER_CONTENT_TYPE: &str = "content-type";
+
+/// Everything that can go wrong reading or writing a message.
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    /// The stream ended part way through a message.
+    UnexpectedEof,
+    MalformedHeader(String),
+    MissingContentLength,
+    InvalidContentLength(String),
+    UnsupportedCharset(String),
+    MessageTooLarge(usize),
+    InvalidUtf8(std::string::FromUtf8Error),
+    InvalidJson(serde_json::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Io(e)                   => write!(f, "{}", e),
+            CodecError::UnexpectedEof           => write!(f, "unexpected end of stream part way through a message"),
+            CodecError::MalformedHeader(s)      => write!(f, "malformed header: {:?}", s),
+            CodecError::MissingContentLength    => write!(f, "missing Content-Length header"),
+            CodecError::InvalidContentLength(s) => write!(f, "invalid Content-Length: {:?}", s),
+            CodecError::UnsupportedCharset(s)   => write!(f, "unsupported charset: {:?}", s),
+            CodecError::MessageTooLarge(len)    => write!(f, "message of {} bytes exceeds the maximum of {} bytes", len, MAX_MESSAGE_SIZE),
+            CodecError::InvalidUtf8(e)          => write!(f, "message isn't valid UTF-8: {}", e),
+            CodecError::InvalidJson(e)          => write!(f, "message isn't valid JSON: {}", e),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => CodecError::UnexpectedEof,
+            _                            => CodecError::Io(e),
+        }
+    }
+}
+
+/// Reads messages off a stream, blocking until each one has arrived in full.
+pub struct MessageReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> MessageReader<R> {
+    pub fn new(reader: R) -> Self { Self { reader } }
+
+    /// Reads the next message, or `None` if the stream ended cleanly between messages.
+    pub fn read_message(&mut self) -> Result<Option<Value>, CodecError> {
+        let mut content_length = None;
+        let mut line = String::new();
+        let mut first = true;
+
+        loop {
+            line.clear();
+            let n = (&mut self.reader).take(MAX_HEADER_LINE).read_line(&mut line)?;
+            if n == 0 {
+                return if first { Ok(None) } else { Err(CodecError::UnexpectedEof) }
+            }
+            if !line.ends_with('\n') {
+                return if (n as u64) < MAX_HEADER_LINE { Err(CodecError::UnexpectedEof) } else { Err(CodecError::MalformedHeader(line)) }
+            }
+            first = false;
+
+            if !read_header(&line, &mut content_length)? { break }
+        }
+
+        // message body isn't newline terminated, so we read content_length bytes
+        let mut body = vec![0; body_length(content_length)?];
+        self.reader.read_exact(&mut body)?;
+        parse_body(body).map(Some)
+    }
+}
+
+/// Takes in a header line, returning false for the empty line that ends the headers.
+fn read_header(line: &str, content_length: &mut Option<usize>) -> Result<bool, CodecError> {
+    let header = line.trim_end_matches(['\r', '\n']);
+    if header.is_empty() { return Ok(false) }
+    let (name, value) = parse_header(header)?;
+    match name.to_lowercase().as_ref() {
+        HEADER_CONTENT_LENGTH => *content_length = Some(parse_content_length(value)?),
+        HEADER_CONTENT_TYPE   => check_content_type(value)?,
+        _                     => (), // the spec only defines the two, so ignore the rest
+    }
+    Ok(true)
+}
+
+fn body_length(content_length: Option<usize>) -> Result<usize, CodecError> {
+    let content_length = content_length.ok_or(CodecError::MissingContentLength)?;
+    if content_length > MAX_MESSAGE_SIZE { return Err(CodecError::MessageTooLarge(content_length)) }
+    Ok(content_length)
+}
+
+fn parse_body(body: Vec<u8>) -> Result<Value, CodecError> {
+    let body = String::from_utf8(body).map_err(CodecError::InvalidUtf8)?;
+    serde_json::from_str(&body).map_err(CodecError::InvalidJson)
+}
+
+/// Splits a header line into its name and value.
+fn parse_header(s: &str) -> Result<(&str, &str), CodecError> {
+    match s.find(':') {
+        Some(i) if !s[..i].trim().is_empty() => Ok((s[..i].trim(), s[i + 1..].trim())),
+        _                                    => Err(CodecError::MalformedHeader(s.to_owned())),
+    }
+}
+
+fn parse_content_length(value: &str) -> Result<usize, CodecError> {
+    value.parse().map_err(|_| CodecError::InvalidContentLength(value.to_owned()))
+}
+
+/// Accepts any content type, as long as its charset (if given) is UTF-8,
+/// e.g. `application/vscode-jsonrpc; charset=utf-8`.
+fn check_content_type(value: &str) -> Result<(), CodecError> {
+    for param in value.split(';').skip(1) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next().unwrap_or("").trim();
+        let val = kv.next().unwrap_or("").trim().trim_matches('"');
+        if key.eq_ignore_ascii_case("charset") && !val.eq_ignore_ascii_case("utf-8") && !val.eq_ignore_ascii_case("utf8") {
+            return Err(CodecError::UnsupportedCharset(val.to_owned()))
+        }
+    }
+    Ok(())
+}
+
+/// Writes messages to a stream, flushing after each one.
+///
+/// Each message goes out in a single `write_all`, so writers that serialise those
+/// (see `client::SharedWriter`) can be shared between threads.
+pub struct MessageWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(writer: W) -> Self { Self { writer } }
+
+    pub fn get_ref(&self) -> &W { &self.writer }
+
+    pub fn write_message(&mut self, msg: &Value) -> Result<(), CodecError> {
+        self.writer.write_all(&frame(msg)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn frame(msg: &Value) -> Result<Vec<u8>, CodecError> {
+    let body = serde_json::to_string(msg).map_err(CodecError::InvalidJson)?;
+    Ok(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes())
+}
+
+/// The async counterparts of `MessageReader` and `MessageWriter`, for `async_client`.
+#[cfg(feature = "async")]
+pub mod nonblocking {
+    use serde_json::Value;
+    use tokio::io::{ AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, };
+
+    use super::*;
+
+    pub struct AsyncMessageReader<R> {
+        reader: R,
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncMessageReader<R> {
+        pub fn new(reader: R) -> Self { Self { reader } }
+
+        /// Reads the next message, or `None` if the stream ended cleanly between messages.
+        pub async fn read_message(&mut self) -> Result<Option<Value>, CodecError> {
+            let mut content_length = None;
+            let mut line = String::new();
+            let mut first = true;
+
+            loop {
+                line.clear();
+                let n = (&mut self.reader).take(MAX_HEADER_LINE).read_line(&mut line).await?;
+                if n == 0 {
+                    return if first { Ok(None) } else { Err(CodecError::UnexpectedEof) }
+                }
+                if !line.ends_with('\n') {
+                    return if (n as u64) < MAX_HEADER_LINE { Err(CodecError::UnexpectedEof) } else { Err(CodecError::MalformedHeader(line)) }
+                }
+                first = false;
+                if !read_header(&line, &mut content_length)? { break }
+            }
+
+            let mut body = vec![0; body_length(content_length)?];
+            self.reader.read_exact(&mut body).await?;
+            parse_body(body).map(Some)
+        }
+    }
+
+    pub struct AsyncMessageWriter<W> {
+        writer: W,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncMessageWriter<W> {
+        pub fn new(writer: W) -> Self { Self { writer } }
+
+        pub async fn write_message(&mut self, msg: &Value) -> Result<(), CodecError> {
+            self.writer.write_all(&frame(msg)?).await?;
+            self.writer.flush().await?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::quickcheck;
+    use super::*;
+
+    fn read_all(bytes: &[u8]) -> Result<Vec<Value>, CodecError> {
+        let mut reader = MessageReader::new(bytes);
+        let mut msgs = Vec::new();
+        while let Some(msg) = reader.read_message()? {
+            msgs.push(msg);
+        }
+        Ok(msgs)
+    }
+
+    fn encode(msg: &Value) -> Vec<u8> {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_message(msg).unwrap();
+        writer.writer
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"rootUri": "file:///tmp/ü"}});
+        assert_eq!(read_all(&encode(&msg)).unwrap(), vec![msg]);
+    }
+
+    #[test]
+    fn content_type_with_charset() {
+        let bytes = b"Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}";
+        assert_eq!(read_all(bytes).unwrap(), vec![json!({})]);
+        let bytes = b"Content-Type: application/vscode-jsonrpc; charset=\"UTF8\"\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(read_all(bytes).unwrap(), vec![json!({})]);
+        let bytes = b"Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=utf-16\r\n\r\n{}";
+        assert!(matches!(read_all(bytes), Err(CodecError::UnsupportedCharset(_))));
+    }
+
+    #[test]
+    fn lenient_headers() {
+        let bytes = b"content-length:2\nX-Whatever: 1\n\n{}";
+        assert_eq!(read_all(bytes).unwrap(), vec![json!({})]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert!(matches!(read_all(b"Content-Length 2\r\n\r\n{}"), Err(CodecError::MalformedHeader(_))));
+        assert!(matches!(read_all(b"Content-Length: two\r\n\r\n{}"), Err(CodecError::InvalidContentLength(_))));
+        assert!(matches!(read_all(b"Content-Type: application/json\r\n\r\n{}"), Err(CodecError::MissingContentLength)));
+    }
+
+    #[test]
+    fn eof() {
+        assert!(read_all(b"").unwrap().is_empty());
+        assert!(matches!(read_all(b"Content-Length: 2\r\n"), Err(CodecError::UnexpectedEof)));
+        assert!(matches!(read_all(b"Content-Len"), Err(CodecError::UnexpectedEof)));
+        assert!(matches!(read_all(b"Content-Length: 10\r\n\r\n{}"), Err(CodecError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn bad_bodies() {
+        assert!(matches!(read_all(b"Content-Length: 2\r\n\r\n\xff\xfe"), Err(CodecError::InvalidUtf8(_))));
+        assert!(matches!(read_all(b"Content-Length: 2\r\n\r\n{]"), Err(CodecError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn too_large() {
+        let bytes = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_SIZE + 1);
+        assert!(matches!(read_all(bytes.as_bytes()), Err(CodecError::MessageTooLarge(_))));
+    }
+
+    #[test]
+    fn header_line_too_long() {
+        let bytes = vec![b'X'; MAX_HEADER_LINE as usize + 1];
+        assert!(matches!(read_all(&bytes), Err(CodecError::MalformedHeader(_))));
+    }
+
+    quickcheck! {
+        fn prop_round_trips(methods: Vec<String>, ids: Vec<i64>) -> bool {
+            let msgs: Vec<Value> = methods.iter().zip(ids.iter())
+                .map(|(method, id)| json!({"jsonrpc": "2.0", "id": id, "method": method, "params": [method]}))
+                .collect();
+            let bytes: Vec<u8> = msgs.iter().flat_map(encode).collect();
+            read_all(&bytes).unwrap() == msgs
+        }
+
+        fn prop_never_panics_on_garbage(bytes: Vec<u8>) -> bool {
+            let _ = read_all(&bytes);
+            true
+        }
+
+        fn prop_never_panics_on_garbage_after_headers(len: usize, body: Vec<u8>) -> bool {
+            let mut bytes = format!("Content-Length: {}\r\n\r\n", len % 64).into_bytes();
+            bytes.extend(body);
+            let _ = read_all(&bytes);
+            true
+        }
+
+        fn prop_truncated_messages_are_errors(method: String, cut: usize) -> bool {
+            let bytes = encode(&json!({"jsonrpc": "2.0", "method": method}));
+            let cut = 1 + cut % (bytes.len() - 1);
+            MessageReader::new(&bytes[..cut]).read_message().is_err()
+        }
+    }
+}
@@ -0,0 +1,495 @@
+//! Renders `textDocument/publishDiagnostics` notifications the way scalac reports problems:
+//! `path:line:column: severity: message`, followed by the offending line with its range underlined.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{ Path, PathBuf, };
+
+use serde_json::Value;
+
+use crate::client;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity { Error, Warning, Information, Hint }
+
+impl Severity {
+    /// From the LSP `DiagnosticSeverity`, which defaults to an error when it's missing.
+    fn from_lsp(severity: Option<i64>) -> Severity {
+        match severity {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _       => Severity::Error,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error       => "error",
+            Severity::Warning     => "warning",
+            Severity::Information => "info",
+            Severity::Hint        => "hint",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error       => "\x1b[31m",
+            Severity::Warning     => "\x1b[33m",
+            Severity::Information |
+            Severity::Hint        => "\x1b[36m",
+        }
+    }
+}
+
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A position in a document, zero-based as in the LSP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position { pub line: usize, pub character: usize }
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub    start: Position,
+    pub      end: Position,
+    pub  message: String,
+}
+
+impl Diagnostic {
+    pub fn from_json(json: &Value) -> Option<Diagnostic> {
+        let position = |json: &Value| Some(Position {
+                 line: json["line"].as_u64()? as usize,
+            character: json["character"].as_u64()? as usize,
+        });
+        Some(Diagnostic {
+            severity: Severity::from_lsp(json["severity"].as_i64()),
+               start: position(&json["range"]["start"])?,
+                 end: position(&json["range"]["end"])?,
+             message: json["message"].as_str()?.to_owned(),
+        })
+    }
+}
+
+/// The diagnostics published for one file, from the params of a `textDocument/publishDiagnostics`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDiagnostics {
+    pub         uri: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl FileDiagnostics {
+    pub fn from_params(params: &Value) -> Option<FileDiagnostics> {
+        Some(FileDiagnostics {
+                    uri: params["uri"].as_str()?.to_owned(),
+            diagnostics: params["diagnostics"].as_array()?.iter().filter_map(Diagnostic::from_json).collect(),
+        })
+    }
+
+    /// The file's path, relative to the given directory where possible.
+    pub fn path(&self, base: &Path) -> PathBuf {
+        let path = uri_to_path(&self.uri);
+        path.strip_prefix(base).map(Path::to_path_buf).unwrap_or(path)
+    }
+}
+
+/// Turns a `file://` URI into a path, decoding any percent-encoded bytes.
+pub fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => { decoded.push(b); i += 3 },
+            (b, _)          => { decoded.push(b); i += 1 },
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Renders diagnostics as they're published, holding on to the latest diagnostics of each file for
+/// the summary and any machine-readable report.
+pub struct Renderer {
+       colors: bool,
+         base: PathBuf,
+    published: Vec<FileDiagnostics>,
+}
+
+impl Renderer {
+    pub fn new(colors: bool, base: PathBuf) -> Renderer {
+        Renderer { colors, base, published: Vec::new() }
+    }
+
+    /// Forgets the diagnostics published so far, e.g. as a watch starts its next build.
+    pub fn reset(&mut self) { self.published.clear() }
+
+    /// The diagnostics published so far, in the order their files were first published;
+    /// as in the LSP, each publication replaces the previous diagnostics of its file.
+    pub fn published(&self) -> &[FileDiagnostics] { &self.published }
+
+    /// Renders all the diagnostics of a file, reading the file for the source excerpts.
+    pub fn render(&mut self, file: &FileDiagnostics) -> String {
+        let source = fs::read_to_string(uri_to_path(&file.uri)).ok();
+        self.render_with_source(file, source.as_ref().map(String::as_ref))
+    }
+
+    fn render_with_source(&mut self, file: &FileDiagnostics, source: Option<&str>) -> String {
+        match self.published.iter_mut().find(|f| f.uri == file.uri) {
+            Some(published) => *published = file.clone(),
+            None            => self.published.push(file.clone()),
+        }
+
+        let path = file.path(&self.base);
+        let lines: Vec<&str> = source.map(|s| s.lines().collect()).unwrap_or_default();
+        let mut out = String::new();
+        for diagnostic in &file.diagnostics {
+            let severity = diagnostic.severity;
+            let _ = writeln!(out, "{}:{}:{}: {}: {}",
+                self.paint(BOLD, &path.display().to_string()),
+                diagnostic.start.line + 1,
+                diagnostic.start.character + 1,
+                self.paint(severity.color(), severity.label()),
+                diagnostic.message,
+            );
+            if let Some(line) = lines.get(diagnostic.start.line) {
+                let _ = writeln!(out, "{}", line);
+                let _ = writeln!(out, "{}", self.paint(severity.color(), &underline(line, diagnostic)));
+            }
+        }
+        out
+    }
+
+    /// `1 error and 2 warnings found`, or nothing if there are neither, counting each file's
+    /// latest diagnostics only.
+    pub fn summary(&self) -> Option<String> {
+        let severities = || self.published.iter().flat_map(|file| &file.diagnostics).map(|d| d.severity);
+        let errors = severities().filter(|&severity| severity == Severity::Error).count();
+        let warnings = severities().filter(|&severity| severity == Severity::Warning).count();
+        let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+        let summary = match (errors, warnings) {
+            (0, 0) => return None,
+            (e, 0) => count(e, "error"),
+            (0, w) => count(w, "warning"),
+            (e, w) => format!("{} and {}", count(e, "error"), count(w, "warning")),
+        };
+        Some(format!("{} found", summary))
+    }
+
+    fn paint(&self, color: &str, s: &str) -> String {
+        if self.colors { format!("{}{}{}", color, s, RESET) } else { s.to_owned() }
+    }
+}
+
+/// The caret line under a source line: spaces (keeping tabs, so it lines up) up to the start of the
+/// range, then carets to its end, or to the end of the line if the range spans several lines.
+fn underline(line: &str, diagnostic: &Diagnostic) -> String {
+    let len = line.chars().count();
+    let start = diagnostic.start.character.min(len);
+    let end = if diagnostic.end.line == diagnostic.start.line { diagnostic.end.character.min(len) } else { len };
+    let mut underline: String = line.chars().take(start).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    underline.push_str(&"^".repeat((end.saturating_sub(start)).max(1)));
+    underline
+}
+
+/// The machine-readable formats diagnostics can be reported in, for CI and code quality tools.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A JSON array of diagnostics, with one-based lines and columns.
+    Json,
+    /// SARIF 2.1.0, as ingested by GitHub code scanning and most code quality dashboards.
+    Sarif,
+    /// GitHub Actions workflow commands, which annotate the lines of pull requests.
+    Github,
+    /// Checkstyle XML, as ingested by Jenkins and friends.
+    Checkstyle,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "json"       => Some(Format::Json),
+            "sarif"      => Some(Format::Sarif),
+            "github"     => Some(Format::Github),
+            "checkstyle" => Some(Format::Checkstyle),
+            _            => None,
+        }
+    }
+}
+
+/// Reports the given diagnostics in the given format, with paths relative to `base` where possible.
+pub fn report(format: Format, files: &[FileDiagnostics], base: &Path) -> String {
+    match format {
+        Format::Json       => report_json(files, base),
+        Format::Sarif      => report_sarif(files, base),
+        Format::Github     => report_github(files, base),
+        Format::Checkstyle => report_checkstyle(files, base),
+    }
+}
+
+/// Every diagnostic along with the path of its file.
+fn each<'a>(files: &'a [FileDiagnostics], base: &'a Path) -> impl Iterator<Item = (String, &'a Diagnostic)> + 'a {
+    files.iter().flat_map(move |file| {
+        let path = file.path(base).display().to_string();
+        file.diagnostics.iter().map(move |diagnostic| (path.clone(), diagnostic))
+    })
+}
+
+fn report_json(files: &[FileDiagnostics], base: &Path) -> String {
+    let diagnostics: Vec<Value> = each(files, base).map(|(path, d)| json!({
+        "file": path,
+        "line": d.start.line + 1,
+        "column": d.start.character + 1,
+        "endLine": d.end.line + 1,
+        "endColumn": d.end.character + 1,
+        "severity": d.severity.label(),
+        "message": d.message,
+    })).collect();
+    format!("{:#}\n", Value::Array(diagnostics))
+}
+
+/// SARIF wants URIs: relative ones against the `%SRCROOT%` of the run where possible, so that code
+/// scanning can find the files in the repository.
+fn report_sarif(files: &[FileDiagnostics], base: &Path) -> String {
+    let location = |path: &str| {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            json!({"uri": client::file_uri(path)})
+        } else {
+            json!({"uri": client::uri_path(path), "uriBaseId": "%SRCROOT%"})
+        }
+    };
+    let results: Vec<Value> = each(files, base).map(|(path, d)| json!({
+        "level": match d.severity {
+            Severity::Error       => "error",
+            Severity::Warning     => "warning",
+            Severity::Information |
+            Severity::Hint        => "note",
+        },
+        "message": {"text": d.message},
+        "locations": [{
+            "physicalLocation": {
+                "artifactLocation": location(&path),
+                "region": {
+                    "startLine": d.start.line + 1,
+                    "startColumn": d.start.character + 1,
+                    "endLine": d.end.line + 1,
+                    "endColumn": d.end.character + 1,
+                },
+            },
+        }],
+    })).collect();
+    let sarif = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "sbtl",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/dwijnand/sbtl",
+                },
+            },
+            "originalUriBaseIds": {"%SRCROOT%": {"uri": format!("{}/", client::file_uri(base))}},
+            "results": results,
+        }],
+    });
+    format!("{:#}\n", sarif)
+}
+
+fn report_github(files: &[FileDiagnostics], base: &Path) -> String {
+    // https://docs.github.com/en/actions/using-workflow-commands-for-github-actions
+    let escape_data = |s: &str| s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A");
+    let escape_property = |s: &str| escape_data(s).replace(':', "%3A").replace(',', "%2C");
+    each(files, base).map(|(path, d)| {
+        let command = match d.severity {
+            Severity::Error       => "error",
+            Severity::Warning     => "warning",
+            Severity::Information |
+            Severity::Hint        => "notice",
+        };
+        format!("::{} file={},line={},col={},endLine={},endColumn={}::{}\n",
+            command, escape_property(&path), d.start.line + 1, d.start.character + 1,
+            d.end.line + 1, d.end.character + 1, escape_data(&d.message))
+    }).collect()
+}
+
+fn report_checkstyle(files: &[FileDiagnostics], base: &Path) -> String {
+    let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;").replace('\'', "&apos;").replace('\n', "&#10;");
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<checkstyle version=\"4.3\">\n");
+    for file in files.iter().filter(|f| !f.diagnostics.is_empty()) {
+        let _ = writeln!(out, "  <file name=\"{}\">", escape(&file.path(base).display().to_string()));
+        for d in &file.diagnostics {
+            let severity = match d.severity {
+                Severity::Error       => "error",
+                Severity::Warning     => "warning",
+                Severity::Information |
+                Severity::Hint        => "info",
+            };
+            let _ = writeln!(out, "    <error line=\"{}\" column=\"{}\" severity=\"{}\" message=\"{}\" source=\"scalac\"/>",
+                d.start.line + 1, d.start.character + 1, severity, escape(&d.message));
+        }
+        out.push_str("  </file>\n");
+    }
+    out.push_str("</checkstyle>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Value {
+        json!({
+            "uri": "file:///home/me/my%20project/src/main/scala/Foo.scala",
+            "diagnostics": [
+                {
+                    "range": {"start": {"line": 1, "character": 17}, "end": {"line": 1, "character": 18}},
+                    "severity": 1,
+                    "message": "type mismatch;\n found   : Int(1)\n required: String",
+                },
+                {
+                    "range": {"start": {"line": 2, "character": 2}, "end": {"line": 3, "character": 0}},
+                    "severity": 2,
+                    "message": "a pure expression does nothing in statement position",
+                },
+            ],
+        })
+    }
+
+    const SOURCE: &str = "object Foo {\n  val x: String = 1\n\t42\n}\n";
+
+    #[test]
+    fn parse_params() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        assert_eq!(file.diagnostics.len(), 2);
+        assert_eq!(file.diagnostics[0].severity, Severity::Error);
+        assert_eq!(file.diagnostics[1].start, Position { line: 2, character: 2 });
+        assert_eq!(file.path(Path::new("/home/me/my project")), PathBuf::from("src/main/scala/Foo.scala"));
+        assert_eq!(file.path(Path::new("/elsewhere")), PathBuf::from("/home/me/my project/src/main/scala/Foo.scala"));
+    }
+
+    #[test]
+    fn render_like_scalac() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let mut renderer = Renderer::new(false, PathBuf::from("/home/me/my project"));
+        assert_eq!(renderer.render_with_source(&file, Some(SOURCE)), "\
+src/main/scala/Foo.scala:2:18: error: type mismatch;
+ found   : Int(1)
+ required: String
+  val x: String = 1
+                 ^
+src/main/scala/Foo.scala:3:3: warning: a pure expression does nothing in statement position
+\t42
+\t ^
+");
+        assert_eq!(renderer.summary(), Some("1 error and 1 warning found".to_owned()));
+    }
+
+    #[test]
+    fn render_without_source() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let mut renderer = Renderer::new(false, PathBuf::from("/home/me/my project"));
+        let rendered = renderer.render_with_source(&file, None);
+        assert_eq!(rendered.lines().count(), 4);
+    }
+
+    #[test]
+    fn render_with_colors() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let mut renderer = Renderer::new(true, PathBuf::from("/home/me/my project"));
+        let rendered = renderer.render_with_source(&file, Some(SOURCE));
+        assert!(rendered.contains("\x1b[31merror\x1b[0m"));
+        assert!(rendered.contains("\x1b[33m\t ^\x1b[0m"));
+    }
+
+    #[test]
+    fn republishing_replaces() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let mut renderer = Renderer::new(false, PathBuf::new());
+        renderer.render_with_source(&file, None);
+        renderer.render_with_source(&FileDiagnostics { diagnostics: Vec::new(), ..file.clone() }, None);
+        assert_eq!(renderer.published(), &[FileDiagnostics { diagnostics: Vec::new(), ..file }]);
+    }
+
+    fn report_of(format: Format) -> String {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        report(format, &[file], Path::new("/home/me/my project"))
+    }
+
+    #[test]
+    fn json_report() {
+        let json: Value = serde_json::from_str(&report_of(Format::Json)).unwrap();
+        assert_eq!(json[0], json!({
+            "file": "src/main/scala/Foo.scala", "line": 2, "column": 18, "endLine": 2, "endColumn": 19,
+            "severity": "error", "message": "type mismatch;\n found   : Int(1)\n required: String",
+        }));
+        assert_eq!(json[1]["severity"], "warning");
+    }
+
+    #[test]
+    fn sarif_report() {
+        let sarif: Value = serde_json::from_str(&report_of(Format::Sarif)).unwrap();
+        assert_eq!(sarif["version"], "2.1.0");
+        let results = &sarif["runs"][0]["results"];
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(results[0]["locations"][0]["physicalLocation"]["artifactLocation"], json!({"uri": "src/main/scala/Foo.scala", "uriBaseId": "%SRCROOT%"}));
+        assert_eq!(results[0]["locations"][0]["physicalLocation"]["region"]["startLine"], 2);
+        assert_eq!(results[1]["level"], "warning");
+        assert_eq!(sarif["runs"][0]["originalUriBaseIds"]["%SRCROOT%"]["uri"], "file:///home/me/my%20project/");
+
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let sarif: Value = serde_json::from_str(&report(Format::Sarif, &[file], Path::new("/elsewhere"))).unwrap();
+        let location = &sarif["runs"][0]["results"][0]["locations"][0]["physicalLocation"]["artifactLocation"];
+        assert_eq!(location, &json!({"uri": "file:///home/me/my%20project/src/main/scala/Foo.scala"}));
+    }
+
+    #[test]
+    fn github_report() {
+        assert_eq!(report_of(Format::Github), "\
+::error file=src/main/scala/Foo.scala,line=2,col=18,endLine=2,endColumn=19::type mismatch;%0A found   : Int(1)%0A required: String
+::warning file=src/main/scala/Foo.scala,line=3,col=3,endLine=4,endColumn=1::a pure expression does nothing in statement position
+");
+    }
+
+    #[test]
+    fn checkstyle_report() {
+        assert_eq!(report_of(Format::Checkstyle), "\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<checkstyle version=\"4.3\">
+  <file name=\"src/main/scala/Foo.scala\">
+    <error line=\"2\" column=\"18\" severity=\"error\" message=\"type mismatch;&#10; found   : Int(1)&#10; required: String\" source=\"scalac\"/>
+    <error line=\"3\" column=\"3\" severity=\"warning\" message=\"a pure expression does nothing in statement position\" source=\"scalac\"/>
+  </file>
+</checkstyle>
+");
+    }
+
+    #[test]
+    fn summaries() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let (error, warning) = (file.diagnostics[0].clone(), file.diagnostics[1].clone());
+        let mut renderer = Renderer::new(false, PathBuf::new());
+        assert_eq!(renderer.summary(), None);
+        renderer.render_with_source(&FileDiagnostics { diagnostics: vec![error.clone(), error], ..file.clone() }, None);
+        assert_eq!(renderer.summary(), Some("2 errors found".to_owned()));
+        renderer.render_with_source(&FileDiagnostics { diagnostics: vec![warning], ..file }, None);
+        assert_eq!(renderer.summary(), Some("1 warning found".to_owned()));
+    }
+
+    #[test]
+    fn republishing_recounts() {
+        let file = FileDiagnostics::from_params(&params()).unwrap();
+        let mut renderer = Renderer::new(false, PathBuf::new());
+        renderer.render_with_source(&file, None);
+        renderer.render_with_source(&file, None);
+        assert_eq!(renderer.summary(), Some("1 error and 1 warning found".to_owned()));
+        renderer.render_with_source(&FileDiagnostics { uri: "file:///p/Bar.scala".to_owned(), ..file }, None);
+        assert_eq!(renderer.summary(), Some("2 errors and 2 warnings found".to_owned()));
+        renderer.reset();
+        assert_eq!(renderer.summary(), None);
+    }
+}
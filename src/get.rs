@@ -0,0 +1,106 @@
+//! `sbtl get <key> [--json]`, for reading a setting of the build, e.g. `scalaVersion` or
+//! `core/version`, from the running sbt server, without running a command and scraping its logs.
+//!
+//! The server can only evaluate settings, so tasks, e.g. `Test/fullClasspath`, aren't supported.
+
+use std::io::prelude::*;
+use std::process::exit;
+
+use serde_json::Value;
+
+use crate::client;
+use crate::client::Session;
+use crate::sbt_client;
+use crate::timings;
+
+pub fn run<I: Iterator<Item = String>>(mut args: I) {
+    let mut key = None;
+    let mut json = false;
+    let mut start = false;
+    while let Some(arg) = args.next() {
+        let mut skip_arg = || { args.next(); };
+        match arg.as_ref() {
+            "--json"                   => json = true,
+            "--client"                 => start = true,
+            // the launcher's, for when it starts a server, and the client's
+            "-v" | "-timings"          => (),
+            "-project-root" | "-connect-timeout" | "-read-timeout" | "-jvm-debug" | "-sbt-jar" | "-timings-trace" => skip_arg(),
+            s if s.starts_with("-D") || s.starts_with("-J") => (),
+            _ if arg.starts_with('-')  => { die!("unknown option {}, usage: sbtl get <key> [--json]", arg); },
+            _ if key.is_none()         => key = Some(arg),
+            _                          => { die!("usage: sbtl get <key> [--json]"); },
+        }
+    }
+    let key = key.unwrap_or_else(|| { die!("usage: sbtl get <key> [--json]"); });
+
+    let server = client::require_server(start);
+    let mut session = Session::connect(server.stream, server.token);
+    let value = setting(&mut session, &key);
+    session.close();
+
+    timings::report();
+    match value {
+        Ok(value)    => println!("{}", format_value(&value, json)),
+        Err(message) => { eprintln!("[error] {}", message); exit(1) },
+    }
+}
+
+/// The value of the setting; tasks, e.g. `Test/fullClasspath`, have to be run for theirs, which
+/// `sbt/setting` doesn't do.
+fn setting<R: BufRead, W: Write>(session: &mut Session<R, W>, key: &str) -> Result<Value, String> {
+    let id = session.send_request("sbt/setting", &sbt_client::setting_params(key));
+    match session.await_response(id) {
+        Ok(result)                                            => {
+            result.get("value").cloned().ok_or_else(|| result["message"].as_str().unwrap_or("the sbt server didn't give a value").to_owned())
+        },
+        Err(ref error) if error.message.contains("is a task") => {
+            Err(format!("{} is a task, and the sbt server only gives the values of settings; run `sbtl --client show {}` for it", key, key))
+        },
+        Err(error)                                            => Err(error.message),
+    }
+}
+
+/// Formats the value as JSON or, for the shell, plainly: strings as they are, and sequences
+/// (e.g. `crossScalaVersions`) one element per line.
+fn format_value(value: &Value, json: bool) -> String {
+    fn plain(value: &Value) -> String {
+        match value {
+            Value::String(s) => s.clone(),
+            _                => value.to_string(),
+        }
+    }
+    match value {
+        _ if json              => value.to_string(),
+        Value::Array(elements) => elements.iter().map(plain).collect::<Vec<_>>().join("\n"),
+        _                      => plain(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    use crate::codec::MessageWriter;
+
+    #[test]
+    fn formats_values() {
+        assert_eq!(format_value(&json!("2.13.12"), false), "2.13.12");
+        assert_eq!(format_value(&json!("2.13.12"), true), "\"2.13.12\"");
+        assert_eq!(format_value(&json!(["2.12.18", "2.13.12"]), false), "2.12.18\n2.13.12");
+        assert_eq!(format_value(&json!(["2.12.18", "2.13.12"]), true), r#"["2.12.18","2.13.12"]"#);
+        assert_eq!(format_value(&json!(true), false), "true");
+        assert_eq!(format_value(&json!({"organization": "com.example"}), false), r#"{"organization":"com.example"}"#);
+    }
+
+    #[test]
+    fn tasks_arent_settings() {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_message(&json!({"jsonrpc": "2.0", "id": 1, "result": {"value": "2.13.12", "contentType": "java.lang.String"}})).unwrap();
+        writer.write_message(&json!({"jsonrpc": "2.0", "id": 2, "error": {"code": -32602, "message": "Key Test / fullClasspath is a task, can only evaluate settings"}})).unwrap();
+        let mut session = Session::new(io::Cursor::new(writer.get_ref().clone()), Vec::new());
+        assert_eq!(setting(&mut session, "scalaVersion"), Ok(json!("2.13.12")));
+        assert_eq!(setting(&mut session, "Test/fullClasspath"),
+            Err("Test/fullClasspath is a task, and the sbt server only gives the values of settings; run `sbtl --client show Test/fullClasspath` for it".to_owned()));
+    }
+}
@@ -6,51 +6,112 @@
 
 const sbt_release_version: &str = "0.13.16";
 
+use std::cell::OnceCell;
 use std::env;
-use std::ffi::OsStr;
+use std::error::Error;
+use std::ffi::{ OsStr, OsString, };
+use std::fmt;
 use std::fmt::Display;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::{ BufReader, BufWriter, };
 use std::io::prelude::*;
 use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
 use std::path::{ Path, PathBuf, };
-use std::process::{ Command, exit, };
+use std::process::{ Child, Command, Stdio, exit, };
+use std::time::UNIX_EPOCH;
+
+use serde_json::Value;
 
-use curl;
-use duct;
 use void::Void;
 
+use crate::log;
+use crate::project;
+use crate::properties::Properties;
+use crate::timings;
+
 lazy_static! {
     static ref HOME: PathBuf = {
         #[allow(deprecated)] // TODO: Switch to the dirs crate.
         env::home_dir().expect("failed to get the path of the current user's home directory")
     };
-    static ref WD: PathBuf = {
-        env::current_dir().expect("failed to get the current working directory")
-    };
     static ref script_name: String = {
         let current_exe = env::current_exe().expect("failed to get the full filesystem path of the current running executable");
         current_exe.file_name().expect("current_exe's file_name should not be '..'").to_string_lossy().into_owned()
     };
-    static ref ARGS: Vec<String> = env::args().collect();
     static ref sbt_launch_dir: PathBuf = PathBuf::from(&*HOME).join(".sbt/launchers");
+    /// The versions of the java binaries we've probed, see `cached_java_version`.
+    static ref java_versions_file: PathBuf = PathBuf::from(&*HOME).join(".sbt/sbtl/java-versions.json");
 }
 
-macro_rules! die(($($arg:tt)*) => (println!("Aborting {}", format!($($arg)*)); ::std::process::exit(1);));
-
-fn build_props_sbt() -> String {
-    File::open("project/build.properties")
-        .ok()
-        .and_then(|f|
-            BufReader::new(f)
-                .lines()
-                .map(|l| l.expect("reading lines from build properties wouldn't fail"))
-                .find(|l| l.starts_with("sbt.version"))
-                .map(|l| l.split('=').nth(1).expect("an sbt version on the right of sbt.version=").trim().to_owned())
-        )
-        .unwrap_or_else(|| "".to_owned())
+pub fn build_props_sbt() -> String { build_props_sbt_at(&project::ROOT) }
+
+/// The `sbt.version` in the `project/build.properties` of the project at `root`, or else "".
+pub fn build_props_sbt_at(root: &Path) -> String {
+    build_props_at(root).get("sbt.version").unwrap_or("").trim().to_owned()
+}
+
+/// The `project/build.properties` of the project at `root`, which is empty if there isn't one.
+pub fn build_props_at(root: &Path) -> Properties {
+    read_build_props(root).unwrap_or_else(|e| { die!("{}", e); })
+}
+
+fn read_build_props(root: &Path) -> Result<Properties, LaunchError> {
+    let path = root.join("project/build.properties");
+    match Properties::load(&path) {
+        Ok(props)                                        => Ok(props),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Properties::default()),
+        Err(e)                                           => Err(LaunchError::Read(path, e)),
+    }
+}
+
+/// Everything that can stop sbt being launched.
+#[derive(Debug)]
+pub enum LaunchError {
+    /// The directory has neither a build.sbt nor a project directory.
+    NotAnSbtProject(PathBuf),
+    /// The sbt launcher jar for the sbt version couldn't be downloaded.
+    Download { url: String, jar: PathBuf, error: String },
+    /// Running `java -version` failed, or it didn't say which version it is.
+    JavaVersion { java: String, error: String },
+    Read(PathBuf, io::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LaunchError::NotAnSbtProject(root)        => write!(f, "{} doesn't appear to be an sbt project.", root.display()),
+            LaunchError::Download { url, jar, error } => write!(f, "Download of {} failed: {}. Obtain the jar manually and place it at {}", url, error, jar.display()),
+            LaunchError::JavaVersion { java, error }  => write!(f, "failed to get the version of {}: {}", java, error),
+            LaunchError::Read(path, e)                => write!(f, "failed to read {}: {}", path.display(), e),
+            LaunchError::Io(e)                        => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for LaunchError {}
+
+impl From<io::Error> for LaunchError {
+    fn from(e: io::Error) -> Self { LaunchError::Io(e) }
+}
+
+/// The properties of the sbt launcher that `project/build.properties` can set too, e.g.
+/// `sbt.boot.properties=project/sbt.boot.properties` for its own boot configuration, or
+/// `sbt.repository.config` for its own repositories.
+const LAUNCHER_PROPERTIES: [&str; 4] = ["sbt.boot.properties", "sbt.repository.config", "sbt.override.build.repos", "sbt.boot.directory"];
+
+/// The JVM options that pass the launcher properties in the build properties on to the launcher,
+/// with relative paths made relative to the project root.
+fn launcher_props_opts(props: &Properties, root: &Path) -> Vec<String> {
+    LAUNCHER_PROPERTIES.iter().filter_map(|&key| {
+        let value = props.get(key)?.trim();
+        let is_path = key != "sbt.override.build.repos" && !value.contains(":/");
+        let value = if is_path && Path::new(value).is_relative() { root.join(value).display().to_string() } else { value.to_owned() };
+        Some(format!("-D{}={}", key, value))
+    }).collect()
 }
 
 fn url_base(version: &str) -> &'static str {
@@ -84,37 +145,167 @@ fn jar_file(version: &str) -> PathBuf {
     PathBuf::from(&*sbt_launch_dir).join(version).join("sbt-launch.jar")
 }
 
-fn get_java_version(java_cmd: &str) -> String {
-    cmd!(java_cmd, "-version")
+fn get_java_version(java_cmd: &str) -> Result<String, LaunchError> {
+    let failed = |error: String| LaunchError::JavaVersion { java: java_cmd.to_owned(), error };
+    let version = cmd!(java_cmd, "-version")
         .stderr_to_stdout()
         .read()
-        .unwrap_or_else(|_| panic!("failed to execute {java} -version", java=java_cmd))
+        .map_err(|e| failed(e.to_string()))?
         .lines()
         .filter(|l| l.contains("java version") || l.contains("openjdk version")) // grep -E -e '(java|openjdk) version'
         .filter_map(|l| l.split_whitespace().nth(2))                             // awk '{ print $3 }'
         .map(|l| l.chars().filter(|c| *c != '"').collect::<String>())  // tr -d \"
         .collect::<Vec<_>>()
-        .join("\n")
+        .join("\n");
+    if version.is_empty() { return Err(failed("`-version` didn't say".to_owned())) }
+    Ok(version)
+}
+
+/// The major version of java from the version `java -version` gives, e.g. 8 for `1.8.0_292`, as
+/// it was before 9, or 17 for `17.0.9` or `17-ea`.
+fn java_major_version(version: &str) -> Option<u8> {
+    let version = version.strip_prefix("1.").unwrap_or(version);
+    let major = version.find(|c: char| !c.is_ascii_digit()).map_or(version, |end| &version[..end]);
+    major.parse().ok()
+}
+
+/// Resolves the java command to the binary it runs, through PATH and any symlinks, e.g. `java` to
+/// `/usr/lib/jvm/java-17-openjdk/bin/java`.
+fn resolve_command(cmd: &str) -> Option<PathBuf> {
+    let path = if cmd.contains('/') {
+        PathBuf::from(cmd)
+    } else {
+        env::split_paths(&env::var_os("PATH")?).map(|dir| dir.join(cmd)).find(|path| path.is_file())?
+    };
+    path.canonicalize().ok()
+}
+
+/// What tells us a java binary's the same one we probed before: replacing it, e.g. upgrading it
+/// in place, changes its size or modification time.
+fn java_stamp(java: &Path) -> Option<Value> {
+    let metadata = fs::metadata(java).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(json!({"size": metadata.len(), "mtime": mtime.as_nanos() as u64}))
+}
+
+/// The version of the java binary, from the cache if it's the same binary as was probed last,
+/// or else from `probe`, which is then cached, keyed by the binary's path.
+/// Returns whether it came from the cache too.
+fn cached_java_version<E, F: FnOnce() -> Result<String, E>>(cache: &Path, java: &Path, probe: F) -> Result<(String, bool), E> {
+    let key = java.to_string_lossy().into_owned();
+    let stamp = match java_stamp(java) { Some(stamp) => stamp, None => return Ok((probe()?, false)) };
+    let mut versions: Value = fs::read(cache).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .filter(Value::is_object)
+        .unwrap_or_else(|| json!({}));
+    let entry = &versions[&key];
+    if let Some(version) = entry["version"].as_str().filter(|_| entry["size"] == stamp["size"] && entry["mtime"] == stamp["mtime"]) {
+        return Ok((version.to_owned(), true))
+    }
+
+    let version = probe()?;
+    let mut entry = stamp;
+    entry["version"] = json!(version);
+    versions[&key] = entry;
+    // written whole and moved into place, so other sbtls never read half of it
+    let tmp = cache.with_extension(format!("json.{}", std::process::id()));
+    let _ = cache.parent().map(fs::create_dir_all);
+    if fs::write(&tmp, versions.to_string()).and_then(|_| fs::rename(&tmp, cache)).is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok((version, false))
 }
 
-fn download_url(sbt_version: &str, url: &str, jar: &Path) -> bool {
+/// The value of the given `-option <value>` argument, for options that are needed before the
+/// arguments are parsed in full, e.g. `-project-root`.
+pub fn option_arg<I: Iterator<Item = String>>(args: I, name: &str) -> Option<String> {
+    let mut args = args.take_while(|arg| arg != "--"); // the rest are someone else's
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return Some(args.next().unwrap_or_else(|| { die!("{} requires an argument", name); }))
+        }
+    }
+    None
+}
+
+/// The options that apply whatever sbtl's doing, and whether each takes an argument.
+const GLOBAL_OPTIONS: [(&str, bool); 9] = [
+    ("-project-root", true), ("-timings", false), ("-timings-trace", true), ("-connect-timeout", true),
+    ("-read-timeout", true), ("-d", false), ("-w", false), ("-q", false), ("-no-colors", false),
+];
+
+/// sbtl's arguments, with the options that apply whatever it's doing, e.g. `-project-root`, taken
+/// out, wherever they are (up to a `--`), so a subcommand, e.g. `bsp`, can be found and given
+/// only its own. Those that are read as they're needed, e.g. by `project::ROOT`, are just skipped.
+#[derive(Debug, PartialEq)]
+pub struct GlobalArgs {
+    pub log_level: log::Level,
+       pub colors: bool,
+         pub rest: Vec<String>,
+}
+
+impl GlobalArgs {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> GlobalArgs {
+        let mut global = GlobalArgs { log_level: log::Level::Info, colors: true, rest: Vec::new() };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--"         => { global.rest.push(arg); global.rest.extend(args.by_ref()); },
+                "-d"         => global.log_level = log::Level::Debug,
+                "-w"         => global.log_level = log::Level::Warn,
+                "-q"         => global.log_level = log::Level::Error,
+                "-no-colors" => global.colors = false,
+                _            => match GLOBAL_OPTIONS.iter().find(|(name, _)| *name == arg) {
+                    Some((_, true)) => if args.next().is_none() { die!("{} requires an argument", arg); },
+                    Some(_)         => (),
+                    None            => global.rest.push(arg),
+                },
+            }
+        }
+        global
+    }
+
+    /// The subcommand, e.g. `server` or `bsp`, if that's what sbtl's been asked to run.
+    pub fn subcommand(&self) -> Option<&str> {
+        self.rest.first().map(String::as_str).filter(|arg| ["server", "bsp", "get", "reload"].contains(arg))
+    }
+
+    /// The arguments of the subcommand.
+    pub fn subcommand_args(self) -> impl Iterator<Item = String> {
+        self.rest.into_iter().skip(1)
+    }
+}
+
+fn download_url(sbt_version: &str, url: &str, jar: &Path) -> Result<(), LaunchError> {
     eprintln!("Downloading sbt launcher for {}:", sbt_version);
     eprintln!("  From  {}", url);
     eprintln!("    To  {}", jar.display());
 
-    fs::create_dir_all(jar.parent().unwrap()).unwrap();
-
-    let mut jar2 = BufWriter::new(File::create(jar).unwrap());
-    let mut easy = curl::easy::Easy::new();
-    easy.follow_location(true).unwrap();
-    easy.url(url).unwrap();
-    easy.write_function(move |data| Ok(jar2.write(data).unwrap())).unwrap();
-    easy.perform().unwrap();
-    File::open(jar).is_ok()
+    let download = || -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = jar.parent() { fs::create_dir_all(dir)? }
+        let mut jar2 = BufWriter::new(File::create(jar)?);
+        let mut easy = curl::easy::Easy::new();
+        easy.follow_location(true)?;
+        easy.fail_on_error(true)?; // a 404 page isn't a jar
+        easy.url(url)?;
+        let mut transfer = easy.transfer();
+        // writing less than all of it makes curl give up with a write error
+        transfer.write_function(|data| Ok(jar2.write_all(data).map_or(0, |_| data.len())))?;
+        transfer.perform()?;
+        drop(transfer);
+        jar2.flush()?;
+        Ok(())
+    };
+    download().map_err(|e| {
+        let _ = fs::remove_file(jar); // so it's not taken for the jar next time
+        LaunchError::Download { url: url.to_owned(), jar: jar.to_owned(), error: e.to_string() }
+    })
 }
 
+/// Runs sbt, like sbt-extras' `sbt` script: either from the command line's arguments (`run`), or
+/// as set up by a `LauncherBuilder`.
 #[derive(Default)]
 pub struct Launcher {
+                    root: PathBuf,
              sbt_version: String,
     sbt_explicit_version: String,
                  verbose: bool,
@@ -123,26 +314,71 @@ pub struct Launcher {
                  sbt_jar: PathBuf,
                  sbt_new: bool,
            residual_args: Vec<String>,
+    /// The version `java -version` gave, once it's been asked for.
+        java_version_out: OnceCell<String>,
+}
+
+/// Sets up a `Launcher` for running sbt from Rust, e.g.
+/// `Launcher::builder("/p").jvm_opt("-Xmx2g").arg("compile").build()?.command()?`.
+pub struct LauncherBuilder {
+    launcher: Launcher,
+}
+
+impl LauncherBuilder {
+    /// The sbt version to use, rather than the one in `project/build.properties`.
+    pub fn sbt_version<S: Into<String>>(mut self, version: S) -> Self { self.launcher.sbt_explicit_version = version.into(); self }
+
+    /// The sbt launcher jar to use, rather than downloading the one for the sbt version.
+    pub fn sbt_jar<P: Into<PathBuf>>(mut self, path: P) -> Self { self.launcher.sbt_jar = path.into(); self }
+
+    pub fn java_cmd<S: Into<String>>(mut self, cmd: S) -> Self { self.launcher.java_cmd = cmd.into(); self }
+
+    pub fn jvm_opt<S: Into<String>>(mut self, opt: S) -> Self { self.launcher.jvm_opts.push(opt.into()); self }
+
+    /// An argument for sbt itself, e.g. a command to run.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self { self.launcher.residual_args.push(arg.into()); self }
+
+    pub fn verbose(mut self, verbose: bool) -> Self { self.launcher.verbose = verbose; self }
+
+    /// The launcher, if the project root is an sbt project.
+    pub fn build(self) -> Result<Launcher, LaunchError> {
+        self.launcher.check_project()?;
+        Ok(self.launcher)
+    }
 }
 
 impl Launcher {
-    pub fn new() -> Self {
+    /// A launcher for the project at `root`, e.g. `project::ROOT`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
         Self {
+                root: root.into(),
             java_cmd: "java".into(),
             ..Default::default()
         }
     }
 
+    /// Sets up a launcher for the project at `root`.
+    pub fn builder<P: Into<PathBuf>>(root: P) -> LauncherBuilder { LauncherBuilder { launcher: Launcher::new(root) } }
+
+    /// A launcher for the project at `root`, set up from command line arguments (without the
+    /// executable's path).
+    pub fn from_args<P: Into<PathBuf>, I: IntoIterator<Item = String>>(root: P, args: I) -> Self {
+        let mut launcher = Launcher::new(root);
+        launcher.parse_args(args.into_iter());
+        launcher
+    }
+
     // TODO: See if this can become a macro
     fn vlog(&self, s: &str) { if self.verbose { eprintln!("{}", s) } }
 
-    fn set_sbt_version(&mut self) {
+    fn set_sbt_version(&mut self) -> Result<(), LaunchError> {
         if self.sbt_explicit_version.is_empty() {
-            self.sbt_version=build_props_sbt()
+            self.sbt_version=read_build_props(&self.root)?.get("sbt.version").unwrap_or("").trim().to_owned()
         } else {
             self.sbt_version=self.sbt_explicit_version.to_owned()
         }
         if self.sbt_version.is_empty() { self.sbt_version=sbt_release_version.to_owned() }
+        Ok(())
     }
 
     fn add_jvm_opt(&mut self, s: &str) {
@@ -160,28 +396,57 @@ impl Launcher {
         self.add_jvm_opt(&format!("-Xrunjdwp:transport=dt_socket,server=y,suspend=n,address={}", port));
     }
 
-    fn java_version(&self) -> u8 {
-        let version = get_java_version(&self.java_cmd);
+    fn java_version(&self) -> Result<u8, LaunchError> {
+        let version = match self.java_version_out.get() {
+            Some(version) => version,
+            None          => {
+                let version = timings::time("launcher", "java_version", || self.probe_java_version())?;
+                self.java_version_out.get_or_init(|| version)
+            },
+        };
         self.vlog(&format!("Detected Java version: {}", version));
-        version[2..3].to_owned().parse().expect("a java version")
+        java_major_version(version)
+            .ok_or_else(|| LaunchError::JavaVersion { java: self.java_cmd.clone(), error: format!("unrecognised version {:?}", version) })
+    }
+
+    fn probe_java_version(&self) -> Result<String, LaunchError> {
+        let java = match resolve_command(&self.java_cmd) { Some(java) => java, None => return get_java_version(&self.java_cmd) };
+        let (version, cached) = cached_java_version(&java_versions_file, &java, || get_java_version(&self.java_cmd))?;
+        if cached {
+            self.vlog(&format!("Using the java version of {} cached in {}", java.display(), java_versions_file.display()));
+        } else {
+            self.vlog(&format!("Probed the java version of {}, caching it in {}", java.display(), java_versions_file.display()));
+        }
+        Ok(version)
     }
 
     // MaxPermSize critical on pre-8 JVMs but incurs noisy warning on 8+
-    fn default_jvm_opts(&self) -> Vec<String> {
-        let v = self.java_version();
+    fn default_jvm_opts(&self) -> Result<Vec<String>, LaunchError> {
+        let v = self.java_version()?;
         let opts_common = ["-Xms512m", "-Xmx1536m", "-Xss2m"];
         if v >= 8 {
-            opts_common.iter().map(|s| s.to_string()).collect()
+            Ok(opts_common.iter().map(|s| s.to_string()).collect())
         } else {
             let mut opts = Vec::with_capacity(opts_common.len() + 1);
             opts.push("-XX:MaxPermSize=384m");
             opts.extend_from_slice(&opts_common);
-            opts.iter().map(|x| x.to_string()).collect()
+            Ok(opts.iter().map(|x| x.to_string()).collect())
         }
     }
 
     fn exec_runner<S: AsRef<OsStr>>(&self, args: &[S]) {
         self.vlog("# Executing command line:");
+        self.vlog_command_line(args);
+
+        let err = Command::new(&args[0]).args(&args[1..]).current_dir(&self.root).exec();
+        println!("error: {}", err);
+        if let Some(err) = err.raw_os_error() {
+            exit(err);
+        }
+        exit(-1)
+    }
+
+    fn vlog_command_line<S: AsRef<OsStr>>(&self, args: &[S]) {
         if self.verbose {
             for arg in args {
                 let arg = arg.as_ref();
@@ -196,31 +461,26 @@ impl Launcher {
             }
             self.vlog("")
         };
-
-        let err = Command::new(&args[0]).args(&args[1..]).exec();
-        println!("error: {}", err);
-        if let Some(err) = err.raw_os_error() {
-            exit(err);
-        }
-        exit(-1)
     }
 
-    fn acquire_sbt_jar(&mut self) -> bool {
-        ({
+    fn acquire_sbt_jar(&mut self) -> Result<(), LaunchError> {
+        if ({
             self.sbt_jar = jar_file(&self.sbt_version);
             File::open(self.sbt_jar.as_path()).is_ok()
         }) || ({
             self.sbt_jar = PathBuf::from(&*HOME);
             self.sbt_jar.push(format!(".ivy2/local/org.scala-sbt/sbt-launch/{}/jars/sbt-launch.jar", self.sbt_version));
             File::open(self.sbt_jar.as_path()).is_ok()
-        }) || ({
-            self.sbt_jar = jar_file(&self.sbt_version);
-            download_url(&self.sbt_version, &make_url(&self.sbt_version), &self.sbt_jar)
-        })
+        }) {
+            return Ok(())
+        }
+        self.sbt_jar = jar_file(&self.sbt_version);
+        download_url(&self.sbt_version, &make_url(&self.sbt_version), &self.sbt_jar)
     }
 
     fn usage(&mut self) {
-        self.set_sbt_version();
+        self.set_sbt_version().unwrap_or_else(|e| { die!("{}", e); });
+        let default_jvm_opts = self.default_jvm_opts().unwrap_or_else(|e| { die!("{}", e); });
         println!("\
 Usage: {script_name} [options]
 
@@ -231,25 +491,100 @@ are not special.
 
   -h | -help         print this message
   -v                 verbose operation (this runner is chattier)
+  -d, -w, -q         aliases for --debug, --warn, --error
+  -no-colors         disable ANSI color codes
   -jvm-debug <port>  turn on JVM debugging, open at the given port.
   -sbt-jar <path>    use the specified jar as the sbt launcher
+  -timings          report how long each phase of sbtl took, e.g. probing the java version
+  -timings-trace <path>  also write them to the file as Chrome trace events, for chrome://tracing
+  -project-root <dir>  use the given directory as the project root, rather than the nearest one up
+                     from here with a project/build.properties, or else the outermost with a build.sbt
+  --client           run the command on an sbt server, starting one in the background if there isn't one
 
   # passing options to the jvm - note it does NOT use JAVA_OPTS due to pollution
   <default>        {default_jvm_opts}
   -Dkey=val        pass -Dkey=val directly to the jvm
-  -J-X             pass option -X directly to the jvm (-J is stripped)",
+  -J-X             pass option -X directly to the jvm (-J is stripped)
+
+  # when talking to a running sbt server
+  --diagnostics-format <format>  also report diagnostics as json, sarif, github or checkstyle
+  --diagnostics-file <path>      write that report to the given file rather than stdout
+  --junit-xml <dir>              also write the results of the tests run as JUnit XML to the directory
+  -connect-timeout <seconds>     give up connecting to the server after this long (default: 10)
+  -read-timeout <seconds>        give up on a server that's been silent this long (default: never),
+                                 exiting with 124, or 69 if the server goes away mid-command
+
+  # controlling the sbt server running in the background
+  server status             show its pid, sbt version, uptime and socket
+  server stop               ask it to stop, falling back to SIGTERM
+  server restart            stop it and start a new one
+  server logs [-f]          print (and follow) the log of the one --client started
+  get <key> [--json]        print the value of a setting, e.g. scalaVersion or
+                            core/crossScalaVersions, plainly or as json
+  reload                    reload the build, reporting only whether it loaded, or why not
+  ~<command>                with --client, run the command on every change to the sources,
+                            until Enter or Ctrl-C
+
+  # talking to sbt over the Build Server Protocol (sbt 1.4+)
+  bsp targets               list the build targets
+  bsp compile [target...]   compile the given targets, or all of them
+  bsp test [--junit-xml <dir>] [target...]  test the given targets, or all that have tests
+  bsp run [target] [-- args...]  run the given target, or the only one that can be",
             script_name=*script_name,
-            default_jvm_opts=self.default_jvm_opts().join(" "),
+            default_jvm_opts=default_jvm_opts.join(" "),
         )
     }
 
+    /// Runs sbt as the command line says, in place of this process.
     pub fn run(&mut self) {
-        let mut args = ARGS.iter().skip(1); // skip the path of the executable
+        self.parse_args(env::args().skip(1)); // skip the path of the executable
+
+        if self.residual_args.is_empty() {
+            self.vlog(&format!("Starting {}: invoke with -help for other options", *script_name));
+            self.residual_args = vec!["shell".into()];
+        }
+
+        let exec_args = timings::time("launcher", "java_args", || self.java_args()).unwrap_or_else(|e| {
+            println!("{}", e);
+            exit(1)
+        });
+        timings::report(); // as the JVM's about to take our place
+        self.exec_runner(&exec_args)
+    }
+
+    /// The command that runs sbt with the arguments given, or its shell if there aren't any,
+    /// downloading the sbt launcher first if need be.
+    pub fn command(&mut self) -> Result<Command, LaunchError> {
+        if self.residual_args.is_empty() { self.residual_args = vec!["shell".into()] }
+        let exec_args = self.java_args()?;
+        let mut command = Command::new(&exec_args[0]);
+        command.args(&exec_args[1..]).current_dir(&self.root);
+        Ok(command)
+    }
+
+    /// Starts sbt as a server in the background, detached from the terminal (so Ctrl-C here
+    /// doesn't take it down) and with its output going to `log`. Needs sbt 1.4+.
+    pub fn start_server(&mut self, log: File) -> Result<Child, LaunchError> {
+        self.residual_args = vec!["--detach-stdio".into()]; // the commands are for the client to run
+
+        let exec_args = self.java_args()?;
+        self.vlog("# Starting server with command line:");
+        self.vlog_command_line(&exec_args);
+
+        Ok(Command::new(&exec_args[0]).args(&exec_args[1..])
+            .current_dir(&self.root)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .process_group(0)
+            .spawn()?)
+    }
+
+    fn parse_args<I: Iterator<Item = String>>(&mut self, mut args: I) {
         while let Some(arg) = args.next() {
-            let blank = &String::new();
             let mut require_arg = |tpe| {
-                let opt = arg;
-                let arg = args.next().unwrap_or(blank);
+                let opt = &arg;
+                let arg = args.next().unwrap_or_default();
                 if arg.is_empty() || &arg[0..1] == "-" {
                     die!("{opt} requires <{type}> argument", opt=opt, type=tpe);
                 }
@@ -258,51 +593,188 @@ are not special.
             match arg.as_ref() {
                 "-h" | "-help"           => { self.usage(); exit(1) },
                 "-v"                     => self.verbose = true,
+                "-d"                     => self.add_residual("--debug"),
+                "-w"                     => self.add_residual("--warn"),
+                "-q"                     => self.add_residual("--error"),
+                "-no-colors"             => self.add_jvm_opt("-Dsbt.log.noformat=true"),
                 "-jvm-debug"             => { let arg = require_arg("port"); self.add_debugger(arg.parse().unwrap()) },
                 "-sbt-jar"               => { let arg = require_arg("path"); self.sbt_jar = PathBuf::from(arg) },
+                "--client"               => (), // main's already decided whether to run as a client
+                "-project-root"          => { require_arg("dir"); }, // see project::ROOT
+                "-connect-timeout" |
+                "-read-timeout"          => { require_arg("seconds"); eprintln!("[warn] {} only applies when talking to an sbt server, ignoring it", arg) },
+                "-timings"               => (), // see timings
+                "-timings-trace"         => { require_arg("path"); },
+                "--diagnostics-format" |
+                "--diagnostics-file"     => { require_arg("arg"); eprintln!("[warn] {} only applies when talking to an sbt server, ignoring it", arg) },
+                "--junit-xml"            => { require_arg("dir"); eprintln!("[warn] {} only applies when talking to an sbt server, ignoring it", arg) },
                 s if s.starts_with("-D") => self.add_jvm_opt(s),
                 s if s.starts_with("-J") => self.add_jvm_opt(&s[2..]),
-                "new"                    => { self.sbt_new=true; self.sbt_explicit_version=sbt_release_version.to_owned(); self.add_residual(arg) },
+                "new"                    => { self.sbt_new=true; self.sbt_explicit_version=sbt_release_version.to_owned(); self.add_residual(&arg) },
                 s                        => self.add_residual(s),
             }
         }
 
-        let args_count = self.residual_args.len();
-
-        self.set_sbt_version();
-        self.vlog(&format!("Detected sbt version {}", self.sbt_version));
+    }
 
-        if args_count == 0 {
-            self.vlog(&format!("Starting {}: invoke with -help for other options", *script_name));
-            self.residual_args = vec!["shell".into()];
+    /// Whether the project root is an sbt project, unless it's for `sbt new` to make one.
+    fn check_project(&self) -> Result<(), LaunchError> {
+        if File::open(self.root.join("build.sbt")).is_err() && !self.root.join("project").is_dir() && !self.sbt_new {
+            return Err(LaunchError::NotAnSbtProject(self.root.clone()))
         }
+        Ok(())
+    }
+
+    /// The java command line that runs sbt with the options and residual args given.
+    fn java_args(&mut self) -> Result<Vec<OsString>, LaunchError> {
+        self.set_sbt_version()?;
+        self.vlog(&format!("Detected sbt version {}", self.sbt_version));
 
         // verify this is an sbt dir
-        if File::open(PathBuf::from("build.sbt")).is_err() && !PathBuf::from("project").is_dir() && !self.sbt_new {
-            println!("{pwd} doesn't appear to be an sbt project.", pwd=WD.display());
-            exit(1);
-        }
+        self.check_project()?;
 
         // no jar? download it.
         if File::open(self.sbt_jar.as_path()).is_err() {
-            let success = self.acquire_sbt_jar();
-            if !success {
-                // still no jar? uh-oh.
-                println!("Download failed. Obtain the jar manually and place it at {}", self.sbt_jar.display());
-                exit(1);
-            }
+            timings::time("launcher", "acquire_sbt_jar", || self.acquire_sbt_jar())?;
         }
 
         self.vlog("Using default jvm options");
-        let default_jvm_opts=self.default_jvm_opts();
+        let default_jvm_opts = self.default_jvm_opts()?;
+
+        let mut exec_args: Vec<OsString> = Vec::new();
+        exec_args.push(self.java_cmd.clone().into());
+        exec_args.extend(default_jvm_opts.into_iter().map(Into::into));
+        // before those given, so -D on the command line still has the last word
+        for opt in launcher_props_opts(&read_build_props(&self.root)?, &self.root) {
+            self.vlog(&format!("Using {} from project/build.properties", opt));
+            exec_args.push(opt.into());
+        }
+        exec_args.extend(self.jvm_opts.iter().map(Into::into));
+        exec_args.extend(vec!["-jar".into(), self.sbt_jar.clone().into_os_string()]);
+        exec_args.extend(self.residual_args.iter().map(Into::into));
+        Ok(exec_args)
+    }
+}
 
-        let mut exec_args: Vec<&OsStr> = Vec::new();
-        exec_args.push(self.java_cmd.as_ref());
-        exec_args.append(&mut default_jvm_opts.iter().map(AsRef::as_ref).collect());
-        exec_args.append(&mut self.jvm_opts.iter().map(AsRef::as_ref).collect());
-        exec_args.append(&mut vec!["-jar".as_ref(), self.sbt_jar.as_ref()]);
-        exec_args.append(&mut self.residual_args.iter().map(AsRef::as_ref).collect());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        self.exec_runner(&exec_args)
+    #[test]
+    fn option_args() {
+        let args = |args: &[&str]| option_arg(args.iter().map(|s| s.to_string()), "-project-root");
+        assert_eq!(args(&["compile"]), None);
+        assert_eq!(args(&["-v", "-project-root", "../foo", "compile"]), Some("../foo".to_owned()));
+        assert_eq!(args(&["bsp", "run", "--", "-project-root", "x"]), None);
+    }
+
+    #[test]
+    fn global_args() {
+        let args = |args: &[&str]| GlobalArgs::parse(args.iter().map(|s| s.to_string()));
+        let global = args(&["-project-root", "../p", "-q", "bsp", "compile", "-timings", "core"]);
+        assert_eq!(global.subcommand(), Some("bsp"));
+        assert_eq!(global.log_level, log::Level::Error);
+        assert_eq!(global.subcommand_args().collect::<Vec<_>>(), vec!["compile", "core"]);
+        let global = args(&["bsp", "run", "root", "--", "-q", "--", "-project-root"]);
+        assert_eq!(global.log_level, log::Level::Info);
+        assert_eq!(global.subcommand_args().collect::<Vec<_>>(), vec!["run", "root", "--", "-q", "--", "-project-root"]);
+        assert_eq!(args(&["-no-colors", "compile"]), GlobalArgs { log_level: log::Level::Info, colors: false, rest: vec!["compile".to_owned()] });
+        assert_eq!(args(&["compile"]).subcommand(), None);
+    }
+
+    #[test]
+    fn builder() {
+        let root = env::temp_dir().join(format!("sbtl-builder-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        match Launcher::builder(&root).build() {
+            Err(LaunchError::NotAnSbtProject(dir)) => assert_eq!(dir, root),
+            other                                  => panic!("expected NotAnSbtProject, got {:?}", other.map(|_| ())),
+        }
+
+        fs::write(root.join("build.sbt"), "").unwrap();
+        let launcher = Launcher::builder(&root).sbt_version("1.9.7").jvm_opt("-Xmx2g").arg("compile").build().unwrap();
+        assert_eq!(launcher.root, root);
+        assert_eq!(launcher.sbt_explicit_version, "1.9.7");
+        assert_eq!(launcher.jvm_opts, vec!["-Xmx2g"]);
+        assert_eq!(launcher.residual_args, vec!["compile"]);
+        assert_eq!(launcher.java_cmd, "java");
+
+        fs::write(root.join("sbt-launch.jar"), "").unwrap();
+        let mut launcher = Launcher::builder(&root).sbt_jar(root.join("sbt-launch.jar")).java_cmd("/nonexistent/java").build().unwrap();
+        match launcher.command() {
+            Err(LaunchError::JavaVersion { java, .. }) => assert_eq!(java, "/nonexistent/java"),
+            other                                      => panic!("expected JavaVersion, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn command_line_args() {
+        let launcher = Launcher::from_args("/p", vec!["-v".to_owned(), "-J-Xmx2g".to_owned(), "-Dfoo=bar".to_owned(), "compile".to_owned()]);
+        assert!(launcher.verbose);
+        assert_eq!(launcher.jvm_opts, vec!["-Xmx2g", "-Dfoo=bar"]);
+        assert_eq!(launcher.residual_args, vec!["compile"]);
+    }
+
+    #[test]
+    fn java_version_cache() {
+        let dir = env::temp_dir().join(format!("sbtl-java-{}", std::process::id()));
+        let java = dir.join("bin/java");
+        let cache = dir.join("sbtl/java-versions.json");
+        fs::create_dir_all(java.parent().unwrap()).unwrap();
+        fs::write(&java, "#!/bin/sh\n").unwrap();
+
+        let probe = |version: &str| { let version = version.to_owned(); move || Ok::<_, ()>(version) };
+        assert_eq!(cached_java_version(&cache, &java, probe("1.8.0_292")), Ok(("1.8.0_292".to_owned(), false)));
+        assert_eq!(cached_java_version(&cache, &java, || panic!("probed again")), Ok::<_, ()>(("1.8.0_292".to_owned(), true)));
+
+        fs::write(&java, "#!/bin/sh\n# upgraded\n").unwrap(); // a different size
+        assert_eq!(cached_java_version(&cache, &java, || Err(())), Err(()));
+        assert_eq!(cached_java_version(&cache, &java, probe("17.0.9")), Ok(("17.0.9".to_owned(), false)));
+        assert_eq!(cached_java_version(&cache, &java, || panic!("probed again")), Ok::<_, ()>(("17.0.9".to_owned(), true)));
+
+        fs::write(&cache, "not json").unwrap();
+        assert_eq!(cached_java_version(&cache, &java, probe("17.0.9")), Ok(("17.0.9".to_owned(), false)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn java_major_versions() {
+        assert_eq!(java_major_version("1.8.0_292"), Some(8));
+        assert_eq!(java_major_version("1.7.0_80"), Some(7));
+        assert_eq!(java_major_version("11.0.2"), Some(11));
+        assert_eq!(java_major_version("17.0.9"), Some(17));
+        assert_eq!(java_major_version("21"), Some(21));
+        assert_eq!(java_major_version("17-ea"), Some(17));
+        assert_eq!(java_major_version(""), None);
+    }
+
+    #[test]
+    fn resolves_commands() {
+        assert_eq!(resolve_command("sh"), Path::new("/bin/sh").canonicalize().ok());
+        assert_eq!(resolve_command("/nonexistent/java"), None);
+    }
+
+    #[test]
+    fn build_properties() {
+        let root = env::temp_dir().join(format!("sbtl-props-{}", std::process::id()));
+        fs::create_dir_all(root.join("project")).unwrap();
+        assert_eq!(build_props_sbt_at(&root), "");
+        fs::write(root.join("project/build.properties"), "# the sbt version\nsbt.version : 1.9.7\n").unwrap();
+        assert_eq!(build_props_sbt_at(&root), "1.9.7");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn launcher_properties() {
+        let props = Properties::parse("sbt.version=1.9.7\nsbt.boot.properties=project/sbt.boot.properties\n\
+            sbt.repository.config=/etc/sbt/repositories\nsbt.override.build.repos=true\n").unwrap();
+        assert_eq!(launcher_props_opts(&props, Path::new("/p")), vec![
+            "-Dsbt.boot.properties=/p/project/sbt.boot.properties",
+            "-Dsbt.repository.config=/etc/sbt/repositories",
+            "-Dsbt.override.build.repos=true",
+        ]);
+        let props = Properties::parse("sbt.boot.properties=file:///etc/sbt/boot.properties").unwrap();
+        assert_eq!(launcher_props_opts(&props, Path::new("/p")), vec!["-Dsbt.boot.properties=file:///etc/sbt/boot.properties"]);
     }
 }
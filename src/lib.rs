@@ -0,0 +1,31 @@
+//! sbtl as a library: launching sbt, and talking to its server.
+//!
+//! `Launcher` runs sbt the way the `sbtl` command does, and `SbtClient` talks to a running
+//! sbt server over its socket.
+//! Author: Dale Wijnand <dale.wijnand@gmail.com>
+
+#[macro_use] extern crate duct;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate serde_json;
+
+macro_rules! die(($($arg:tt)*) => (println!("Aborting {}", format!($($arg)*)); ::std::process::exit(1);));
+
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod bsp;
+pub mod codec;
+pub mod diagnostics;
+pub mod get;
+pub mod launcher;
+pub mod log;
+pub mod project;
+pub mod properties;
+pub mod client;
+pub mod sbt_client;
+pub mod server;
+pub mod test_report;
+pub mod timings;
+pub mod watch;
+
+pub use crate::launcher::{ LaunchError, Launcher, LauncherBuilder, };
+pub use crate::sbt_client::{ Canceller, ClientError, ExecResult, Notification, SbtClient, Setting, };
@@ -0,0 +1,119 @@
+//! Prints the server's `window/logMessage` notifications the way sbt prints its own log:
+//! every line prefixed with a coloured `[error]`, `[warn]`, `[info]` or `[debug]` label.
+
+use std::io;
+use std::io::Write;
+
+/// sbt's levels, with `Success` (for its `[success] Total time: ...`) shown along with `Info`, but
+/// not under `-w`.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level { Debug, Info, Success, Warn, Error }
+
+impl Level {
+    /// From the LSP `MessageType` of a message. sbt sends its success messages, and others, as
+    /// `Log`, so that's no less than `Info`; only the newer `Debug` is.
+    pub fn from_lsp(message_type: i64, msg: &str) -> Level {
+        match message_type {
+            1                                   => Level::Error,
+            2                                   => Level::Warn,
+            4 if msg.starts_with("Total time:") => Level::Success,
+            3 | 4                               => Level::Info,
+            _                                   => Level::Debug,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Level::Error   => "error",
+            Level::Warn    => "warn",
+            Level::Success => "success",
+            Level::Info    => "info",
+            Level::Debug   => "debug",
+        }
+    }
+
+    /// The colour sbt gives the label, if any.
+    fn color(self) -> Option<&'static str> {
+        match self {
+            Level::Error   => Some("\x1b[31m"),
+            Level::Warn    => Some("\x1b[33m"),
+            Level::Success => Some("\x1b[32m"),
+            _              => None,
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+pub struct Logger {
+    colors: bool,
+     level: Level,
+}
+
+impl Logger {
+    /// A logger that drops messages below the given level.
+    pub fn new(colors: bool, level: Level) -> Logger {
+        Logger { colors, level }
+    }
+
+    pub fn colors(&self) -> bool { self.colors }
+
+    pub fn log(&self, level: Level, msg: &str) {
+        self.log_to(&mut io::stdout(), level, msg)
+    }
+
+    pub fn log_to<W: Write + ?Sized>(&self, out: &mut W, level: Level, msg: &str) {
+        if let Some(formatted) = self.format(level, msg) {
+            let _ = out.write_all(formatted.as_bytes());
+        }
+    }
+
+    fn format(&self, level: Level, msg: &str) -> Option<String> {
+        if level < self.level { return None }
+        let label = match level.color() {
+            Some(color) if self.colors => format!("{}{}{}", color, level.label(), RESET),
+            _                          => level.label().to_owned(),
+        };
+        let lines: Vec<&str> = if msg.is_empty() { vec![""] } else { msg.lines().collect() };
+        Some(lines.iter().map(|line| format!("[{}] {}\n", label, line)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_every_line() {
+        let logger = Logger::new(false, Level::Info);
+        assert_eq!(logger.format(Level::Info, "Compiling 1 Scala source to target"), Some("[info] Compiling 1 Scala source to target\n".to_owned()));
+        assert_eq!(logger.format(Level::Warn, "one\ntwo"), Some("[warn] one\n[warn] two\n".to_owned()));
+    }
+
+    #[test]
+    fn colours_like_sbt() {
+        let logger = Logger::new(true, Level::Debug);
+        assert_eq!(logger.format(Level::Error, "boom"), Some("[\x1b[31merror\x1b[0m] boom\n".to_owned()));
+        assert_eq!(logger.format(Level::Warn, "hmm"), Some("[\x1b[33mwarn\x1b[0m] hmm\n".to_owned()));
+        assert_eq!(logger.format(Level::Info, "ok"), Some("[info] ok\n".to_owned()));
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let logger = Logger::new(false, Level::Warn);
+        assert_eq!(logger.format(Level::Info, "ok"), None);
+        assert_eq!(logger.format(Level::Debug, "ok"), None);
+        assert!(logger.format(Level::Error, "boom").is_some());
+        assert_eq!(logger.format(Level::Success, "Total time: 1 s"), None);
+    }
+
+    #[test]
+    fn levels_from_lsp() {
+        assert_eq!(Level::from_lsp(1, "Total time: 0 s"), Level::Error);
+        assert_eq!(Level::from_lsp(4, "Total time: 1 s"), Level::Success);
+        assert_eq!(Level::from_lsp(4, "loading settings for project root"), Level::Info);
+        assert_eq!(Level::from_lsp(5, "evaluating"), Level::Debug);
+        let logger = Logger::new(false, Level::Info);
+        assert_eq!(logger.format(Level::from_lsp(4, "Total time: 1 s"), "Total time: 1 s"), Some("[success] Total time: 1 s\n".to_owned()));
+    }
+}
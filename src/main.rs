@@ -1,21 +1,25 @@
 //! A Rust port of sbt-extras.
 //! Author: Dale Wijnand <dale.wijnand@gmail.com>
 
-use curl;
-#[macro_use] extern crate duct;
-use jsonrpc_lite;
-#[macro_use] extern crate lazy_static;
-#[macro_use] extern crate serde_json;
-use void;
+use std::env;
 
-use std::fs::File;
-
-mod launcher;
-mod client;
+use sbtl::{ bsp, client, get, launcher, project, server, timings, };
+use sbtl::launcher::GlobalArgs;
 
 fn main() {
-    match File::open("project/target/active.json") {
-        Ok(port_file) => client::talk_to_client(port_file),
-        Err(_)        => launcher::Launcher::new().run(),
+    timings::start();
+    let args = GlobalArgs::parse(env::args().skip(1));
+    let (log_level, colors) = (args.log_level, args.colors);
+    match args.subcommand() {
+        Some("server") => return server::run(args.subcommand_args()),
+        Some("bsp")    => return bsp::run(args.subcommand_args(), log_level, colors),
+        Some("get")    => return get::run(args.subcommand_args()),
+        Some("reload") => return client::reload(args.subcommand_args()),
+        _              => (),
+    }
+    match timings::time("client", "connect", client::find_server) {
+        Some(server)                                 => client::talk_to_client(server),
+        None if env::args().any(|a| a == "--client") => client::start_server_and_talk(),
+        None                                         => launcher::Launcher::new(project::ROOT.clone()).run(),
     }
 }
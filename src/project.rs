@@ -0,0 +1,72 @@
+//! Finds the root of the sbt project, so sbtl can be run from any directory inside it.
+
+use std::env;
+use std::path::{ Path, PathBuf, };
+
+use crate::launcher;
+
+lazy_static! {
+    /// The root of the project: the `-project-root` given, or else the nearest directory up from the
+    /// working directory that looks like an sbt project, or else the working directory (e.g. for `new`).
+    pub static ref ROOT: PathBuf = {
+        let wd = env::current_dir().expect("failed to get the current working directory");
+        match launcher::option_arg(env::args().skip(1), "-project-root") {
+            Some(dir) => {
+                let dir = wd.join(dir);
+                if !dir.is_dir() { die!("-project-root {} isn't a directory", dir.display()); }
+                dir
+            },
+            None      => find_root(&wd).unwrap_or(wd),
+        }
+    };
+}
+
+/// Resolves a path relative to the project root.
+pub fn path<P: AsRef<Path>>(rel: P) -> PathBuf { ROOT.join(rel) }
+
+/// Walks up from `dir` to the nearest directory with a `project/build.properties`, which only the
+/// root of a build has, or else to the outermost one with a `build.sbt`, as subprojects can have
+/// their own.
+pub fn find_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| dir.join("project/build.properties").is_file())
+        .or_else(|| dir.ancestors().filter(|dir| dir.join("build.sbt").is_file()).last())
+        .map(Path::to_path_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+    use super::*;
+
+    #[test]
+    fn finds_the_root_from_subdirectories() {
+        let root = env::temp_dir().join(format!("sbtl-root-{}", process::id()));
+        let src = root.join("src/main/scala");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(root.join("project")).unwrap();
+        assert_eq!(find_root(&src), None);
+
+        fs::write(root.join("project/build.properties"), "sbt.version=1.9.7\n").unwrap();
+        assert_eq!(find_root(&src), Some(root.clone()));
+        assert_eq!(find_root(&root), Some(root.clone()));
+
+        fs::write(src.join("build.sbt"), "").unwrap(); // a subproject's
+        assert_eq!(find_root(&src), Some(root.clone()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skips_the_build_sbt_of_subprojects() {
+        let root = env::temp_dir().join(format!("sbtl-subprojects-{}", process::id()));
+        let src = root.join("core/src/main/scala");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(root.join("core/build.sbt"), "").unwrap();
+        assert_eq!(find_root(&src), Some(root.join("core")));
+
+        fs::write(root.join("build.sbt"), "").unwrap(); // the outermost wins
+        assert_eq!(find_root(&src), Some(root.clone()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Java's `.properties` format, as `project/build.properties` is written in: `key=value`,
+//! `key: value` or `key value` lines, `#` and `!` comments, lines continued with a trailing `\`,
+//! and `\t`, `\n`, `\uXXXX` etc escapes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties {
+    entries: BTreeMap<String, String>,
+}
+
+impl Properties {
+    pub fn get(&self, key: &str) -> Option<&str> { self.entries.get(key).map(String::as_str) }
+
+    /// All the properties, by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Parses the contents of a `.properties` file, where later keys win, like `Properties.load`.
+    pub fn parse(contents: &str) -> Result<Properties, String> {
+        let mut entries = BTreeMap::new();
+        for line in logical_lines(contents) {
+            let (key, value) = split_entry(&line);
+            entries.insert(unescape(key)?, unescape(value)?);
+        }
+        Ok(Properties { entries })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Properties> {
+        let contents = fs::read_to_string(path)?;
+        Properties::parse(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn is_whitespace(c: char) -> bool { c == ' ' || c == '\t' || c == '\x0c' }
+
+/// Whether the line ends with a backslash that isn't itself escaped.
+fn is_continued(line: &str) -> bool {
+    line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+/// Joins continued lines, dropping comments, blank lines and leading whitespace.
+fn logical_lines(contents: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current: Option<String> = None;
+    for line in contents.replace("\r\n", "\n").split(['\n', '\r']) {
+        let line = line.trim_start_matches(is_whitespace);
+        let logical = match current.take() {
+            Some(mut logical) => { logical.push_str(line); logical },
+            None if line.is_empty() || line.starts_with('#') || line.starts_with('!') => continue,
+            None              => line.to_owned(),
+        };
+        if is_continued(&logical) {
+            current = Some(logical[..logical.len() - 1].to_owned());
+        } else {
+            lines.push(logical);
+        }
+    }
+    lines.extend(current); // continued at the end of the file
+    lines
+}
+
+/// Splits a line at the first `=`, `:` or whitespace that isn't escaped, taking the whitespace
+/// around the separator with it.
+fn split_entry(line: &str) -> (&str, &str) {
+    let mut escaped = false;
+    let end = line.char_indices().find(|&(_, c)| {
+        let separator = !escaped && (c == '=' || c == ':' || is_whitespace(c));
+        escaped = !escaped && c == '\\';
+        separator
+    }).map_or(line.len(), |(i, _)| i);
+    let rest = line[end..].trim_start_matches(is_whitespace);
+    let rest = rest.strip_prefix(['=', ':']).unwrap_or(rest).trim_start_matches(is_whitespace);
+    (&line[..end], rest)
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' { unescaped.push(c); continue }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('f') => unescaped.push('\x0c'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                let unit = u16::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4)
+                    .ok_or_else(|| format!("malformed \\uxxxx escape: \\u{}", hex))?;
+                // surrogate pairs come as two escapes
+                match char::from_u32(unit as u32) {
+                    Some(c) => unescaped.push(c),
+                    None    => {
+                        let low = chars.as_str().strip_prefix("\\u").and_then(|rest| rest.get(..4)).and_then(|hex| u16::from_str_radix(hex, 16).ok());
+                        match low.and_then(|low| char::decode_utf16([unit, low]).next()?.ok()) {
+                            Some(c) => { unescaped.push(c); chars.nth(5); },
+                            None    => unescaped.push(char::REPLACEMENT_CHARACTER),
+                        }
+                    },
+                }
+            },
+            Some(c)   => unescaped.push(c), // e.g. `\=`, `\:`, `\ ` and `\\`
+            None      => (),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Vec<(String, String)> {
+        Properties::parse(contents).unwrap().iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn entry(key: &str, value: &str) -> (String, String) { (key.to_owned(), value.to_owned()) }
+
+    #[test]
+    fn separators() {
+        assert_eq!(parse("sbt.version=1.9.7"), vec![entry("sbt.version", "1.9.7")]);
+        assert_eq!(parse("sbt.version:1.9.7"), vec![entry("sbt.version", "1.9.7")]);
+        assert_eq!(parse("sbt.version 1.9.7"), vec![entry("sbt.version", "1.9.7")]);
+        assert_eq!(parse("  sbt.version \t = \t1.9.7  "), vec![entry("sbt.version", "1.9.7  ")]);
+        assert_eq!(parse("key = a=b: c"), vec![entry("key", "a=b: c")]);
+        assert_eq!(parse("flag"), vec![entry("flag", "")]);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert_eq!(parse("# sbt.version=0.13.18\n! also a comment\n\n   \nsbt.version=1.9.7\r\n"), vec![entry("sbt.version", "1.9.7")]);
+    }
+
+    #[test]
+    fn continuation_lines() {
+        assert_eq!(parse("repos = local, \\\n        maven-central\nnext=1"), vec![entry("next", "1"), entry("repos", "local, maven-central")]);
+        assert_eq!(parse("path=C:\\\\\nnext=1"), vec![entry("next", "1"), entry("path", "C:\\")]); // an escaped backslash doesn't continue
+        assert_eq!(parse("key=a\\\r\n  b"), vec![entry("key", "ab")]);
+        assert_eq!(parse("key=a\\"), vec![entry("key", "a")]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parse(r"a\=b\:c\ d=x\ty\u00e9\\"), vec![entry("a=b:c d", "x\ty\u{e9}\\")]);
+        assert_eq!(parse(r"emoji=\ud83d\ude00!"), vec![entry("emoji", "\u{1f600}!")]);
+        assert!(Properties::parse(r"bad=\u00g1").is_err());
+    }
+
+    #[test]
+    fn later_keys_win() {
+        assert_eq!(Properties::parse("sbt.version=1.8.0\nsbt.version=1.9.7").unwrap().get("sbt.version"), Some("1.9.7"));
+    }
+}
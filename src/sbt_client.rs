@@ -0,0 +1,484 @@
+//! A typed client for sbt's server, for talking to sbt from Rust rather than from the command line.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), sbtl::ClientError> {
+//! let mut client = sbtl::SbtClient::connect(std::path::Path::new("."))?;
+//! client.initialize()?;
+//! client.subscribe(|n| if n.method == "window/logMessage" { println!("{}", n.params["message"]) });
+//! let result = client.exec("compile")?;
+//! println!("compile exited with {:?}", result.exit_code);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Unlike the rest of sbtl, which aborts on errors, it returns them all as `ClientError`s, and it
+//! takes nothing from sbtl's command line, e.g. its `-connect-timeout`.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufReader;
+use std::net::Shutdown;
+use std::path::{ Path, PathBuf, };
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicI64, Ordering, };
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::client;
+use crate::client::{ Connection, JsonRpcId, ServerFeatures, ServerUri, SharedWriter, };
+use crate::codec::{ CodecError, MessageReader, MessageWriter, };
+
+/// Everything that can go wrong talking to an sbt server.
+#[derive(Debug)]
+pub enum ClientError {
+    /// There's no port file, so no server running, in the project.
+    NoServer(PathBuf),
+    Io(io::Error),
+    Codec(CodecError),
+    /// The server sent something we didn't expect, or we couldn't make sense of where it is.
+    Protocol(String),
+    /// The server responded to a request with an error.
+    Server { code: i64, message: String, data: Option<Value> },
+    /// The server closed the connection.
+    Disconnected,
+    /// The server doesn't support the request, e.g. `sbt/completion` before sbt 1.4.
+    Unsupported(&'static str),
+}
+
+impl ClientError {
+    /// Whether this is the server saying the request was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            ClientError::Server { code, .. } => *code == client::REQUEST_CANCELLED,
+            _                                => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::NoServer(path)                => write!(f, "no sbt server is running (there's no {})", path.display()),
+            ClientError::Io(e)                         => write!(f, "{}", e),
+            ClientError::Codec(e)                      => write!(f, "{}", e),
+            ClientError::Protocol(s)                   => write!(f, "{}", s),
+            ClientError::Server { code, message, .. }  => write!(f, "the sbt server responded with error {}: {}", code, message),
+            ClientError::Disconnected                  => write!(f, "the sbt server closed the connection"),
+            ClientError::Unsupported(method)           => write!(f, "the sbt server doesn't support {}", method),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self { ClientError::Io(e) }
+}
+
+impl From<CodecError> for ClientError {
+    fn from(e: CodecError) -> Self { ClientError::Codec(e) }
+}
+
+/// The server's URI and token, from the port file of the project at `root`.
+pub(crate) fn read_port_file(root: &Path) -> Result<(String, Option<String>), ClientError> {
+    let port_file = root.join(client::PORT_FILE);
+    let contents = fs::read_to_string(&port_file).map_err(|_| ClientError::NoServer(port_file.clone()))?;
+    let json: Value = serde_json::from_str(&contents)
+        .map_err(|e| ClientError::Protocol(format!("failed to parse {}: {}", port_file.display(), e)))?;
+    let uri = json["uri"].as_str().ok_or_else(|| ClientError::Protocol(format!("no uri in {}", port_file.display())))?;
+    let token = match json["tokenfilePath"].as_str() {
+        Some(path) => Some(client::try_read_token(path).map_err(ClientError::Protocol)?),
+        None       => None,
+    };
+    Ok((uri.to_owned(), token))
+}
+
+pub(crate) fn initialize_params(token: Option<&str>, root: &Path) -> Value {
+    let mut initialization_options = json!({"skipAnalysis": true, "canWork": true});
+    if let Some(token) = token {
+        initialization_options["token"] = json!(token);
+    }
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    json!({
+        "processId": process::id(),
+        "rootUri": client::file_uri(&root),
+        "clientInfo": {"name": "sbtl", "version": env!("CARGO_PKG_VERSION")},
+        "capabilities": {},
+        "initializationOptions": initialization_options,
+    })
+}
+
+pub(crate) fn exec_params(command_line: &str) -> Value {
+    json!({"commandLine": command_line})
+}
+
+pub(crate) fn setting_params(setting: &str) -> Value {
+    json!({"setting": setting})
+}
+
+/// The request to cancel the request with the given id, which sbt takes as a string.
+pub(crate) fn cancel_request(cancel_id: JsonRpcId, id: JsonRpcId) -> Value {
+    client::request(cancel_id, "sbt/cancelRequest", &json!({"id": id.to_string()}))
+}
+
+/// What the server supports, from its capabilities and, as sbt doesn't advertise most of its
+/// methods, the version of sbt it's running, which `sbt/setting` gives (if the server's new
+/// enough to have that).
+pub(crate) fn features(capabilities: &Value, sbt_version: Result<Setting, ClientError>) -> ServerFeatures {
+    let sbt_version = sbt_version.ok().and_then(|setting| setting.value.as_str().map(str::to_owned)).unwrap_or_default();
+    ServerFeatures::new(capabilities, &sbt_version)
+}
+
+/// Whether a read failed for want of anything to read before the read timeout.
+fn timed_out(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// The id of a response, which sbt sometimes gives as a string.
+pub(crate) fn response_id(id: &Value) -> Option<JsonRpcId> {
+    id.as_i64().or_else(|| id.as_str().and_then(|id| id.parse().ok()))
+}
+
+/// The result of a response, or its error.
+pub(crate) fn response_result(msg: &Value) -> Result<Value, ClientError> {
+    match msg.get("error") {
+        Some(error) => Err(ClientError::Server {
+               code: error["code"].as_i64().unwrap_or(0),
+            message: error["message"].as_str().unwrap_or("").to_owned(),
+               data: error.get("data").cloned(),
+        }),
+        None        => Ok(msg["result"].clone()),
+    }
+}
+
+/// Our answer to the requests the server makes of us, none of which we serve.
+pub(crate) fn method_not_found(id: &Value, method: &str) -> Value {
+    let error = json!({"code": client::METHOD_NOT_FOUND, "message": format!("sbtl doesn't support {}", method)});
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+pub(crate) fn completion_items(result: Value) -> Vec<String> {
+    result["items"].as_array().map(|items| items.iter().filter_map(Value::as_str).map(str::to_owned).collect()).unwrap_or_default()
+}
+
+/// A notification from the server, e.g. `window/logMessage` or `textDocument/publishDiagnostics`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub method: String,
+    pub params: Value,
+}
+
+/// How an `sbt/exec` ended, as in `{"status":"Done","exitCode":0}`; servers before sbt 1.4 don't
+/// give the exit code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecResult {
+       pub status: String,
+    pub exit_code: Option<i32>,
+}
+
+impl ExecResult {
+    pub(crate) fn from_result(result: Value) -> ExecResult {
+        ExecResult {
+               status: result["status"].as_str().unwrap_or("").to_owned(),
+            exit_code: result["exitCode"].as_i64().map(|code| code as i32),
+        }
+    }
+}
+
+/// The value of a setting, as `sbt/setting` gives it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting {
+           pub value: Value,
+    pub content_type: String,
+}
+
+impl Setting {
+    pub(crate) fn from_result(result: Value) -> Setting {
+        Setting {
+                   value: result["value"].clone(),
+            content_type: result["contentType"].as_str().unwrap_or("").to_owned(),
+        }
+    }
+}
+
+type Subscriber = Box<dyn FnMut(&Notification) + Send>;
+
+pub struct SbtClient {
+         reader: MessageReader<BufReader<Connection>>,
+         writer: MessageWriter<SharedWriter>,
+        next_id: Arc<AtomicI64>,
+          token: Option<String>,
+           root: PathBuf,
+    subscribers: Vec<Subscriber>,
+       features: ServerFeatures,
+}
+
+impl SbtClient {
+    /// Connects to the server running in the project at `root`, as advertised by its port file.
+    pub fn connect(root: &Path) -> Result<SbtClient, ClientError> {
+        let (uri, token) = read_port_file(root)?;
+        SbtClient::connect_to(&uri, token, root)
+    }
+
+    /// Connects to the server at the given URI, e.g. `local:///path/to/sock`, for the project at `root`.
+    pub fn connect_to(uri: &str, token: Option<String>, root: &Path) -> Result<SbtClient, ClientError> {
+        SbtClient::connect_within(uri, token, root, client::DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    /// Connects as `connect_to` does, giving up if the server doesn't accept the connection in time.
+    pub fn connect_within(uri: &str, token: Option<String>, root: &Path, timeout: Duration) -> Result<SbtClient, ClientError> {
+        let uri = ServerUri::parse(uri).map_err(ClientError::Protocol)?;
+        SbtClient::over(Connection::connect_within(&uri, timeout)?, token, root)
+    }
+
+    /// A client over a connection that's already been made, e.g. by `client::find_server`.
+    pub(crate) fn over(stream: Connection, token: Option<String>, root: &Path) -> Result<SbtClient, ClientError> {
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(SbtClient {
+                 reader: MessageReader::new(reader),
+                 writer: MessageWriter::new(SharedWriter::new(stream)),
+                next_id: Arc::new(AtomicI64::new(1)),
+                  token,
+                   root: root.to_path_buf(),
+            subscribers: Vec::new(),
+               features: ServerFeatures::default(),
+        })
+    }
+
+    /// Performs the `initialize` handshake and `initialized` notification, returning the server's capabilities.
+    pub fn initialize(&mut self) -> Result<Value, ClientError> {
+        let result = self.request("initialize", &initialize_params(self.token.as_deref(), &self.root))?;
+        self.notify("initialized", &json!({}))?;
+        let capabilities = result["capabilities"].clone();
+        let sbt_version = self.setting_query("sbtVersion");
+        self.features = features(&capabilities, sbt_version);
+        Ok(capabilities)
+    }
+
+    /// Gives up waiting on a response once the server's been silent for this long, which by default
+    /// it never does.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), ClientError> {
+        Ok(self.writer.get_ref().set_read_timeout(timeout)?)
+    }
+
+    /// Calls `f` with every notification the server sends while we're waiting on a response.
+    pub fn subscribe<F: FnMut(&Notification) + Send + 'static>(&mut self, f: F) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    /// Runs a command, e.g. `compile` or `testOnly foo.BarSpec`, waiting for it to finish.
+    pub fn exec(&mut self, command_line: &str) -> Result<ExecResult, ClientError> {
+        let id = self.start_exec(command_line)?;
+        self.await_exec(id)
+    }
+
+    /// Starts running a command, returning the id to `await_exec` or `cancel` it with.
+    pub fn start_exec(&mut self, command_line: &str) -> Result<JsonRpcId, ClientError> {
+        self.send_request("sbt/exec", &exec_params(command_line))
+    }
+
+    pub fn await_exec(&mut self, id: JsonRpcId) -> Result<ExecResult, ClientError> {
+        self.await_response(id).map(ExecResult::from_result)
+    }
+
+    /// Completions for a partial command line (sbt 1.4+).
+    pub fn completion(&mut self, query: &str) -> Result<Vec<String>, ClientError> {
+        if !self.features.completion { return Err(ClientError::Unsupported("sbt/completion")) }
+        self.request("sbt/completion", &json!({"query": query})).map(completion_items)
+    }
+
+    /// The value of a setting, e.g. `scalaVersion` or `root/name`.
+    pub fn setting_query(&mut self, setting: &str) -> Result<Setting, ClientError> {
+        self.request("sbt/setting", &setting_params(setting)).map(Setting::from_result)
+    }
+
+    /// Asks the server to cancel a request (sbt 1.4+); the request then ends with an error that
+    /// `is_cancelled`.
+    pub fn cancel(&mut self, id: JsonRpcId) -> Result<(), ClientError> {
+        self.canceller().cancel(id)
+    }
+
+    /// Something to cancel requests with from another thread, while this one waits on them.
+    pub fn canceller(&self) -> Canceller {
+        Canceller { writer: self.writer.get_ref().clone(), next_id: self.next_id.clone() }
+    }
+
+    /// Performs the `shutdown` request and `exit` notification, which stops the server; its
+    /// hanging up rather than responding is taken to be it stopping.
+    pub fn shutdown(mut self) -> Result<(), ClientError> {
+        match self.request("shutdown", &Value::Null) {
+            Ok(_) | Err(ClientError::Disconnected) => (),
+            Err(e)                                 => return Err(e),
+        }
+        let _ = self.notify("exit", &Value::Null);
+        self.close();
+        Ok(())
+    }
+
+    /// Closes the connection, leaving the server running.
+    pub fn close(self) {
+        let _ = self.writer.get_ref().shutdown(Shutdown::Both);
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, ClientError> {
+        let id = self.send_request(method, params)?;
+        self.await_response(id)
+    }
+
+    fn send_request(&mut self, method: &str, params: &Value) -> Result<JsonRpcId, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.writer.write_message(&client::request(id, method, params))?;
+        Ok(id)
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> Result<(), ClientError> {
+        Ok(self.writer.write_message(&client::notification(method, params))?)
+    }
+
+    /// Reads messages until the response to the given request, passing notifications on to the
+    /// subscribers and turning down any requests from the server.
+    fn await_response(&mut self, id: JsonRpcId) -> Result<Value, ClientError> {
+        loop {
+            let msg = match self.reader.read_message() {
+                Ok(Some(msg))                                => msg,
+                Ok(None) | Err(CodecError::UnexpectedEof)    => return Err(ClientError::Disconnected),
+                Err(CodecError::Io(ref e)) if timed_out(e)   => {
+                    return Err(ClientError::Io(io::Error::new(io::ErrorKind::TimedOut, "the sbt server didn't respond in time")))
+                },
+                Err(e)                                       => return Err(e.into()),
+            };
+            match (msg.get("id"), msg["method"].as_str()) {
+                (Some(msg_id), None) if response_id(msg_id) == Some(id) => return response_result(&msg),
+                (Some(msg_id), Some(method)) => self.writer.write_message(&method_not_found(msg_id, method))?,
+                (None, Some(method))         => {
+                    let notification = Notification { method: method.to_owned(), params: msg["params"].clone() };
+                    for subscriber in &mut self.subscribers {
+                        subscriber(&notification);
+                    }
+                },
+                _                            => (), // a response to some other request, e.g. a cancellation
+            }
+        }
+    }
+}
+
+/// Cancels requests of an `SbtClient`, see `SbtClient::canceller`.
+#[derive(Clone)]
+pub struct Canceller {
+     writer: SharedWriter,
+    next_id: Arc<AtomicI64>,
+}
+
+impl Canceller {
+    pub fn cancel(&self, id: JsonRpcId) -> Result<(), ClientError> {
+        let cancel_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        Ok(MessageWriter::new(self.writer.clone()).write_message(&cancel_request(cancel_id, id))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+    use std::thread;
+    use super::*;
+
+    /// A client talking to a fake server that answers each of its messages with the given replies.
+    fn client_with_server(replies: Vec<Vec<Value>>) -> (SbtClient, thread::JoinHandle<Vec<Value>>) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut reader = MessageReader::new(BufReader::new(theirs.try_clone().unwrap()));
+            let mut writer = MessageWriter::new(theirs);
+            let mut received = Vec::new();
+            for replies in replies {
+                received.push(reader.read_message().unwrap().unwrap());
+                for reply in replies { writer.write_message(&reply).unwrap() }
+            }
+            received
+        });
+        let stream = Connection::Unix(ours);
+        let client = SbtClient {
+                 reader: MessageReader::new(BufReader::new(stream.try_clone().unwrap())),
+                 writer: MessageWriter::new(SharedWriter::new(stream)),
+                next_id: Arc::new(AtomicI64::new(1)),
+                  token: None,
+                   root: PathBuf::from("/p"),
+            subscribers: Vec::new(),
+               features: ServerFeatures::default(),
+        };
+        (client, server)
+    }
+
+    #[test]
+    fn exec_with_notifications() {
+        let (mut client, server) = client_with_server(vec![vec![
+            json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": 3, "message": "compiling"}}),
+            json!({"jsonrpc": "2.0", "id": 7, "method": "window/workDoneProgress/create", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": 1, "result": {"status": "Done", "exitCode": 1}}),
+        ], vec![]]);
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let sink = logged.clone();
+        client.subscribe(move |n| sink.lock().unwrap().push(n.params["message"].clone()));
+
+        assert_eq!(client.exec("compile").unwrap(), ExecResult { status: "Done".to_owned(), exit_code: Some(1) });
+        assert_eq!(*logged.lock().unwrap(), vec![json!("compiling")]);
+        let received = server.join().unwrap();
+        assert_eq!(received[0]["params"]["commandLine"], "compile");
+        assert_eq!(received[1]["id"], 7); // turned down the server's request
+        assert_eq!(received[1]["error"]["code"], client::METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn errors() {
+        let (mut client, _server) = client_with_server(vec![vec![
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32800, "message": "cancelled"}}),
+        ], vec![]]); // then hangs up
+        let id = client.start_exec("test").unwrap();
+        let e = client.await_exec(id).unwrap_err();
+        assert!(e.is_cancelled());
+        assert!(matches!(client.setting_query("name"), Err(ClientError::Disconnected)));
+    }
+
+    #[test]
+    fn completion_needs_sbt_1_4() {
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "result": {"capabilities": {}}});
+        let (mut client, _server) = client_with_server(vec![vec![initialize.clone()], vec![], vec![
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"value": "1.3.13", "contentType": "java.lang.String"}}),
+        ]]);
+        client.initialize().unwrap();
+        assert!(matches!(client.completion("testO"), Err(ClientError::Unsupported("sbt/completion"))));
+
+        let (mut client, server) = client_with_server(vec![vec![initialize], vec![], vec![
+            json!({"jsonrpc": "2.0", "id": 2, "result": {"value": "1.9.7", "contentType": "java.lang.String"}}),
+        ], vec![
+            json!({"jsonrpc": "2.0", "id": 3, "result": {"items": ["testOnly"]}}),
+        ]]);
+        client.initialize().unwrap();
+        assert_eq!(client.completion("testO").unwrap(), vec!["testOnly"]);
+        assert_eq!(server.join().unwrap()[2]["params"], json!({"setting": "sbtVersion"}));
+    }
+
+    #[test]
+    fn shutdown_when_the_server_hangs_up() {
+        let (client, server) = client_with_server(vec![vec![]]); // stopping without a response
+        client.shutdown().unwrap();
+        assert_eq!(server.join().unwrap()[0]["method"], "shutdown");
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (ours, _theirs) = UnixStream::pair().unwrap(); // a server that never answers
+        let mut client = SbtClient::over(Connection::Unix(ours), None, Path::new("/p")).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(matches!(client.exec("compile"), Err(ClientError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn no_server() {
+        assert!(matches!(SbtClient::connect(Path::new("/nonexistent")), Err(ClientError::NoServer(_))));
+    }
+}
@@ -0,0 +1,218 @@
+//! `sbtl server status|stop|restart|logs`, for seeing and controlling the sbt server running
+//! in the background, e.g. one started by `--client`.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::process::exit;
+use std::thread;
+use std::time::{ Duration, Instant, };
+
+use crate::client;
+use crate::client::{ Connection, ServerUri, };
+use crate::launcher;
+use crate::project;
+use crate::sbt_client::{ ClientError, SbtClient, };
+
+/// How long to give the server to stop after asking it to, before resorting to SIGTERM.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to give the server to stop after SIGTERM.
+const TERM_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn run<I: Iterator<Item = String>>(mut args: I) {
+    match args.next().as_deref() {
+        Some("status")  => status(),
+        Some("stop")    => { if !stop() { println!("no sbt server is running") } },
+        Some("restart") => restart(),
+        Some("logs")    => logs(args.any(|arg| arg == "-f")),
+        _               => { die!("usage: sbtl server status|stop|restart|logs [-f]"); },
+    }
+}
+
+fn status() {
+    let server = match client::locate_server() {
+        Ok(Some(server)) => server,
+        Ok(None)         => { println!("no sbt server is running"); exit(1) },
+        Err(server)      => {
+            println!("sbt server isn't responding: {}", server.error);
+            println!("      pid: {}", client::server_pid().map_or("unknown".to_owned(), |pid| pid.to_string()));
+            println!("      uri: {}", server.uri);
+            exit(1)
+        },
+    };
+    // the server writes its port file once it's up, so that's when it started
+    let uptime = fs::metadata(project::path(client::PORT_FILE)).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+    let pid = pid_of(&server);
+    let uri = server.uri.clone();
+    let running = running_version(server);
+    println!("sbt server is running");
+    println!("      pid: {}", pid.map_or("unknown".to_owned(), |pid| pid.to_string()));
+    println!("  version: {}", format_version(running.as_deref(), &launcher::build_props_sbt()));
+    println!("   uptime: {}", uptime.map_or("unknown".to_owned(), format_duration));
+    match &uri {
+        ServerUri::Local(path) => println!("   socket: {}", path.display()),
+        ServerUri::Tcp(addr)   => println!("  address: tcp://{}", addr),
+    }
+}
+
+/// The version of sbt the server's running, as it tells us through `sbt/setting`, which servers
+/// before sbt 1.1 don't have.
+fn running_version(server: client::RunningServer) -> Option<String> {
+    let mut client = SbtClient::over(server.stream, server.token, &project::ROOT).ok()?;
+    client.set_read_timeout(Some(client::probe_timeout())).ok()?;
+    client.initialize().ok()?;
+    let version = client.setting_query("sbtVersion");
+    client.close();
+    version.ok()?.value.as_str().map(str::to_owned)
+}
+
+/// The running version, pointing out when `project/build.properties` has since moved on from it,
+/// or else the configured one, labelled as such.
+fn format_version(running: Option<&str>, configured: &str) -> String {
+    match running {
+        Some(running) if !configured.is_empty() && running != configured => {
+            format!("{} (project/build.properties says {}, restart to use it)", running, configured)
+        },
+        Some(running)                                                    => running.to_owned(),
+        None if configured.is_empty()                                    => "unknown".to_owned(),
+        None                                                             => format!("unknown ({} configured in project/build.properties)", configured),
+    }
+}
+
+/// Stops the server, if one is running, returning whether one was.
+///
+/// It's asked to stop with the `shutdown` request and, should that fail or it not have stopped
+/// after a while, or should it not be answering at all, sent a SIGTERM.
+fn stop() -> bool {
+    let (uri, pid, asked) = match client::locate_server() {
+        Ok(Some(server)) => {
+            println!("[info] stopping the sbt server");
+            (server.uri.clone(), pid_of(&server), ask_to_stop(server))
+        },
+        Ok(None)         => return false,
+        Err(server)      => (server.uri, client::server_pid(), Err(ClientError::Io(server.error))),
+    };
+    let stopped = || match pid {
+        Some(pid) => !is_alive(pid),
+        None      => Connection::connect(&uri).is_err(),
+    };
+    match asked {
+        Ok(()) if wait_until(STOP_TIMEOUT, stopped) => { client::forget_server_pid(); return true },
+        Ok(())                                      => println!("[warn] the sbt server didn't stop"),
+        Err(e)                                      => println!("[warn] failed to ask the sbt server to stop: {}", e),
+    }
+    match pid {
+        Some(pid) => {
+            println!("[warn] sending the sbt server (pid {}) SIGTERM", pid);
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            if !wait_until(TERM_TIMEOUT, stopped) { die!("the sbt server (pid {}) didn't stop", pid); }
+        },
+        None => { die!("the sbt server didn't stop, and its pid is unknown"); },
+    }
+    let _ = fs::remove_file(project::path(client::PORT_FILE)); // sbt didn't get the chance to
+    client::forget_server_pid();
+    true
+}
+
+/// Sends the server `shutdown`, giving up on it should it not answer in time.
+fn ask_to_stop(server: client::RunningServer) -> Result<(), ClientError> {
+    let mut client = SbtClient::over(server.stream, server.token, &project::ROOT)?;
+    client.set_read_timeout(Some(client::probe_timeout()))?;
+    client.initialize()?;
+    client.shutdown()
+}
+
+fn restart() {
+    stop();
+    drop(client::start_server());
+    println!("[info] sbt server started");
+}
+
+/// Prints the log of the server `--client` started, carrying on printing what's appended to it
+/// if following it, like `tail -f`.
+fn logs(follow: bool) {
+    let log_path = project::path(client::SERVER_LOG);
+    let mut log = File::open(&log_path).unwrap_or_else(|e| {
+        die!("failed to open {} (only servers started by sbtl log there): {}", log_path.display(), e);
+    });
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut pos = io::copy(&mut log, &mut stdout).unwrap_or(0);
+    if !follow { return }
+    loop {
+        thread::sleep(Duration::from_millis(250));
+        let len = log.metadata().map(|m| m.len()).unwrap_or(0);
+        if len < pos { // restarted, so the log was truncated
+            pos = log.seek(SeekFrom::Start(0)).unwrap_or(0);
+        }
+        pos += io::copy(&mut log, &mut stdout).unwrap_or(0);
+        let _ = stdout.flush();
+    }
+}
+
+/// The server's pid, from its socket or else from when `--client` started it.
+fn pid_of(server: &client::RunningServer) -> Option<u32> {
+    server.stream.peer_pid().or_else(client::server_pid)
+}
+
+/// Whether the process is still running (or at least that there's one with that pid).
+pub fn is_alive(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Polls the condition until it holds or the timeout's up, returning whether it held.
+fn wait_until<F: Fn() -> bool>(timeout: Duration, cond: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if cond() { return true }
+        thread::sleep(Duration::from_millis(100));
+    }
+    cond()
+}
+
+/// Formats a duration coarsely, e.g. `2h 5m 3s`.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, mins)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 60 + 4)), "3m 4s");
+        assert_eq!(format_duration(Duration::from_secs(2 * 3600 + 5 * 60 + 3)), "2h 5m 3s");
+        assert_eq!(format_duration(Duration::from_secs(86400 + 3600 + 60 + 1)), "1d 1h 1m");
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(format_version(Some("1.9.7"), "1.9.7"), "1.9.7");
+        assert_eq!(format_version(Some("1.9.7"), ""), "1.9.7");
+        assert_eq!(format_version(Some("1.9.7"), "1.10.0"), "1.9.7 (project/build.properties says 1.10.0, restart to use it)");
+        assert_eq!(format_version(None, "1.3.13"), "unknown (1.3.13 configured in project/build.properties)");
+        assert_eq!(format_version(None, ""), "unknown");
+    }
+
+    #[test]
+    fn liveness() {
+        assert!(is_alive(std::process::id()));
+        assert!(!is_alive(i32::MAX as u32));
+    }
+}
@@ -0,0 +1,473 @@
+//! Test results, gathered from what the server sends while tests run: BSP's test
+//! `build/taskStart`/`build/taskFinish` notifications or, for commands run with `sbt/exec`, the
+//! lines ScalaTest, MUnit and JUnit (through junit-interface) log. They're summarised in a table
+//! per suite and can be written as JUnit XML, for CI to show.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::Value;
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// The lines ScalaTest and sbt log once the tests have run, e.g. `Run completed in 1 second.` and
+/// `Passed: Total 2, Failed 0, Errors 0, Passed 2`.
+const RUN_SUMMARIES: &[&str] = &["Run completed in ", "Total number of tests run:", "Passed: Total ", "Failed: Total ", "Error: Total "];
+
+/// `TestStatus` in BSP's `test-finish`.
+const BSP_PASSED: i64 = 1;
+const BSP_FAILED: i64 = 2;
+
+/// Whether the command line runs tests, e.g. `test`, `core/testOnly foo.BarSpec`, `~testQuick` or
+/// `; compile; Test/test`, so that what it logs is worth looking for their results in.
+pub fn is_test_command(command_line: &str) -> bool {
+    command_line.split(';').any(|command| {
+        let command = command.split('/').map(str::trim).collect::<Vec<_>>().join("/"); // `core / test` is `core/test`
+        let key = command.trim_start_matches('~').split_whitespace().next().unwrap_or("");
+        let task = key.rsplit('/').next().unwrap_or(key);
+        ["test", "testOnly", "testQuick"].contains(&task)
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status { Passed, Failed, Ignored }
+
+#[derive(Debug, PartialEq)]
+pub struct TestCase {
+        pub name: String,
+      pub status: Status,
+    pub duration: Option<Duration>,
+     pub message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+     pub passed: usize,
+     pub failed: usize,
+    pub ignored: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Suite {
+        pub name: String,
+       pub cases: Vec<TestCase>,
+    /// As reported, when all we're told is how many passed, etc, e.g. in a BSP `test-report`.
+    pub reported: Option<Counts>,
+    pub duration: Option<Duration>,
+}
+
+impl Suite {
+    fn new(name: &str) -> Suite {
+        Suite { name: name.to_owned(), cases: Vec::new(), reported: None, duration: None }
+    }
+
+    pub fn counts(&self) -> Counts {
+        if let Some(counts) = self.reported.filter(|_| self.cases.is_empty()) { return counts }
+        let count = |status| self.cases.iter().filter(|case| case.status == status).count();
+        Counts { passed: count(Status::Passed), failed: count(Status::Failed), ignored: count(Status::Ignored) }
+    }
+
+    /// How long the suite took, as reported or else as long as its tests took.
+    pub fn duration(&self) -> Option<Duration> {
+        let timed: Vec<Duration> = self.cases.iter().filter_map(|case| case.duration).collect();
+        self.duration.or_else(|| if timed.is_empty() { None } else { Some(timed.iter().sum()) })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TestReport {
+    pub suites: Vec<Suite>,
+    /// The suite the lines being logged are about.
+    current: Option<String>,
+    /// The index of the last test case, which the lines after a failure add to the message of.
+    failing: Option<(usize, usize)>,
+    /// The names of BSP tasks, by id, for the tests under them to be put in suites of that name.
+    tasks: HashMap<String, String>,
+}
+
+impl TestReport {
+    pub fn is_empty(&self) -> bool { self.suites.is_empty() }
+
+    fn suite(&mut self, name: &str) -> usize {
+        match self.suites.iter().position(|suite| suite.name == name) {
+            Some(i) => i,
+            None    => { self.suites.push(Suite::new(name)); self.suites.len() - 1 },
+        }
+    }
+
+    fn add(&mut self, suite: &str, case: TestCase) {
+        let i = self.suite(suite);
+        let failed = case.status == Status::Failed;
+        self.suites[i].cases.push(case);
+        self.failing = if failed { Some((i, self.suites[i].cases.len() - 1)) } else { None };
+    }
+
+    /// Takes in a `window/logMessage`, picking out any test results in it.
+    pub fn on_log(&mut self, msg: &str) {
+        for line in msg.lines() {
+            self.on_line(&strip_ansi(line));
+        }
+    }
+
+    fn on_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+        // the details of a failure, indented under it, as ScalaTest and MUnit log them
+        if line.starts_with("  ") && !trimmed.starts_with('+') {
+            if let Some((suite, case)) = self.failing {
+                let message = &mut self.suites[suite].cases[case].message;
+                match message {
+                    Some(message) => { message.push('\n'); message.push_str(trimmed) },
+                    None          => *message = Some(trimmed.to_owned()),
+                }
+                return
+            }
+        }
+        self.failing = self.failing.filter(|_| trimmed.is_empty());
+
+        if RUN_SUMMARIES.iter().any(|summary| trimmed.starts_with(summary)) {
+            // the suites are done with, so `- ` lines after this aren't tests, e.g. sbt's `Failed tests:` list
+            self.current = None;
+        } else if let Some(rest) = trimmed.strip_prefix("Test run ") {
+            // junit-interface: `Test run foo.BarTest started` and `... finished: 0 failed, 0 ignored, 2 total, 0.003s`
+            if let Some(suite) = rest.strip_suffix(" started") {
+                self.current = Some(suite.to_owned());
+            } else if let Some((suite, stats)) = rest.split_once(" finished: ") {
+                let i = self.suite(suite);
+                self.suites[i].duration = stats.rsplit(", ").next().and_then(parse_seconds);
+                self.current = None;
+            }
+        } else if let Some(rest) = trimmed.strip_prefix("Test ") {
+            // junit-interface: `Test foo.BarTest.adds finished, took 0.001 sec`, `... failed: msg, took 0.01 sec`, `... ignored`
+            let (test, outcome) = match rest.split_once(' ') { Some(split) => split, None => return };
+            let (suite, name) = test.rsplit_once('.').unwrap_or(("tests", test));
+            let took = outcome.rsplit_once(", took ").and_then(|(_, took)| parse_seconds(took.trim_end_matches(" sec")));
+            let (status, message) = if outcome.starts_with("finished") {
+                (Status::Passed, None)
+            } else if let Some(failure) = outcome.strip_prefix("failed: ") {
+                (Status::Failed, Some(failure.rsplit_once(", took ").map_or(failure, |(msg, _)| msg).to_owned()))
+            } else if outcome == "ignored" {
+                (Status::Ignored, None)
+            } else {
+                return // e.g. `started`
+            };
+            self.add(suite, TestCase { name: name.to_owned(), status, duration: took, message });
+        } else if let Some(rest) = trimmed.strip_prefix("==> ") {
+            // MUnit: `==> X foo.BarSuite.subtracts  0.01s munit.FailException: ...` and `==> i foo.BarSuite.later ignored`
+            let mut words = rest.splitn(3, ' ');
+            let (kind, test, rest) = (words.next().unwrap_or(""), words.next().unwrap_or(""), words.next().unwrap_or("").trim());
+            let suite = self.current.clone().unwrap_or_else(|| test.rsplit_once('.').map_or("tests", |(suite, _)| suite).to_owned());
+            let name = test.strip_prefix(&format!("{}.", suite)).unwrap_or(test).to_owned();
+            let (duration, message) = match rest.split_once(' ') {
+                Some((time, message)) if parse_seconds(time).is_some() => (parse_seconds(time), Some(message.to_owned())),
+                _                                                      => (parse_seconds(rest), None),
+            };
+            match kind {
+                "X"       => self.add(&suite, TestCase { name, status: Status::Failed, duration, message }),
+                "i" | "s" => self.add(&suite, TestCase { name, status: Status::Ignored, duration: None, message: None }),
+                _         => (),
+            }
+        } else if let Some(rest) = trimmed.strip_prefix("+ ") {
+            // MUnit: `  + adds 0.003s`
+            let (name, duration) = match rest.rsplit_once(' ') {
+                Some((name, time)) if parse_seconds(time).is_some() => (name, parse_seconds(time)),
+                _                                                    => (rest, None),
+            };
+            let suite = match self.current.clone() { Some(suite) => suite, None => return };
+            self.add(&suite, TestCase { name: name.to_owned(), status: Status::Passed, duration, message: None });
+        } else if let Some(rest) = trimmed.strip_prefix("- ") {
+            // ScalaTest: `- adds (3 milliseconds)`, `- subtracts *** FAILED *** (5 milliseconds)`, `- later !!! IGNORED !!!`
+            let (rest, duration) = match rest.rsplit_once(" (") {
+                Some((rest, time)) if time.ends_with(" milliseconds)") || time.ends_with(" millisecond)") => {
+                    (rest, time.split(' ').next().and_then(|ms| ms.parse().ok()).map(Duration::from_millis))
+                },
+                _                                                                                         => (rest, None),
+            };
+            let (name, status) = if let Some(name) = rest.strip_suffix(" *** FAILED ***") {
+                (name, Status::Failed)
+            } else if let Some(name) = rest.strip_suffix(" !!! IGNORED !!!") {
+                (name, Status::Ignored)
+            } else {
+                (rest, Status::Passed)
+            };
+            let suite = match self.current.clone() { Some(suite) => suite, None => return }; // a list, not a test
+            self.add(&suite, TestCase { name: name.to_owned(), status, duration, message: None });
+        } else if let Some(suite) = trimmed.strip_suffix(':') {
+            // ScalaTest and MUnit start each suite with its name, e.g. `BarSpec:`
+            if !suite.is_empty() && !suite.contains(char::is_whitespace) {
+                self.current = Some(suite.to_owned());
+            }
+        }
+    }
+
+    /// Takes in a BSP notification, picking out the test tasks starting and finishing.
+    pub fn on_bsp(&mut self, method: &str, params: &Value) {
+        let task = params["taskId"]["id"].as_str().unwrap_or("").to_owned();
+        let parent = params["taskId"]["parents"][0].as_str().and_then(|parent| self.tasks.get(parent)).cloned();
+        let data = &params["data"];
+        match (method, params["dataKind"].as_str()) {
+            ("build/taskStart", Some("test-task"))    => { self.tasks.insert(task, target_name(&data["target"])); },
+            ("build/taskStart", Some("test-start"))   => { self.tasks.insert(task, data["displayName"].as_str().unwrap_or("").to_owned()); },
+            ("build/taskFinish", Some("test-finish")) => {
+                let name = data["displayName"].as_str().unwrap_or("").to_owned();
+                if self.suites.iter().any(|suite| suite.name == name) { return } // the suite the tests were in
+                let status = match data["status"].as_i64() {
+                    Some(BSP_PASSED) => Status::Passed,
+                    Some(BSP_FAILED) => Status::Failed,
+                    _                => Status::Ignored,
+                };
+                let message = data["message"].as_str().map(str::to_owned);
+                self.add(&parent.unwrap_or_else(|| "tests".to_owned()), TestCase { name, status, duration: None, message });
+            },
+            ("build/taskFinish", Some("test-report")) => {
+                let i = self.suite(&target_name(&data["target"]));
+                let count = |key: &str| data[key].as_u64().unwrap_or(0) as usize;
+                let ignored = count("ignored") + count("cancelled") + count("skipped");
+                self.suites[i].reported = Some(Counts { passed: count("passed"), failed: count("failed"), ignored });
+                self.suites[i].duration = data["time"].as_u64().map(Duration::from_millis);
+            },
+            _                                         => (),
+        }
+    }
+
+    /// A table of how many tests passed, failed and were ignored per suite, with how long they took.
+    pub fn summary(&self, colors: bool) -> String {
+        let width = self.suites.iter().map(|suite| suite.name.len()).chain(Some("suite".len())).max().unwrap_or(0);
+        let row = |name: &str, counts: Counts, duration: Option<Duration>| {
+            let paint = |n: usize, color| {
+                let n = format!("{:>7}", n);
+                if colors && n.trim() != "0" { format!("{}{}{}", color, n, RESET) } else { n }
+            };
+            let time = duration.map_or("-".to_owned(), |d| format!("{:.2}s", d.as_secs_f64()));
+            format!("{:<width$}  {:>7}  {}  {}  {:>8}\n", name, counts.passed, paint(counts.failed, RED), paint(counts.ignored, YELLOW), time, width = width)
+        };
+        let mut table = format!("{:<width$}  {:>7}  {:>7}  {:>7}  {:>8}\n", "suite", "passed", "failed", "ignored", "time", width = width);
+        let mut total = Counts::default();
+        let mut total_time = None;
+        for suite in &self.suites {
+            let counts = suite.counts();
+            total.passed += counts.passed;
+            total.failed += counts.failed;
+            total.ignored += counts.ignored;
+            if let Some(d) = suite.duration() { total_time = Some(total_time.unwrap_or_default() + d) }
+            table.push_str(&row(&suite.name, counts, suite.duration()));
+        }
+        table.push_str(&row("total", total, total_time));
+        table
+    }
+
+    /// Writes a `TEST-<suite>.xml` per suite to the directory, in the format of Ant's JUnit task.
+    pub fn write_junit_xml(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for suite in &self.suites {
+            let file_name = format!("TEST-{}.xml", suite.name.replace(|c: char| !c.is_alphanumeric() && c != '.' && c != '-', "_"));
+            fs::write(dir.join(file_name), junit_xml(suite))?;
+        }
+        Ok(())
+    }
+}
+
+fn junit_xml(suite: &Suite) -> String {
+    let counts = suite.counts();
+    let seconds = |d: Option<Duration>| format!("{:.3}", d.unwrap_or_default().as_secs_f64());
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{}\">\n",
+        escape(&suite.name), counts.passed + counts.failed + counts.ignored, counts.failed, counts.ignored, seconds(suite.duration())));
+    for case in &suite.cases {
+        let open = format!("  <testcase classname=\"{}\" name=\"{}\" time=\"{}\"", escape(&suite.name), escape(&case.name), seconds(case.duration));
+        match case.status {
+            Status::Passed  => xml.push_str(&format!("{}/>\n", open)),
+            Status::Ignored => xml.push_str(&format!("{}>\n    <skipped/>\n  </testcase>\n", open)),
+            Status::Failed  => {
+                let message = case.message.as_deref().unwrap_or("");
+                let first_line = message.lines().next().unwrap_or("");
+                xml.push_str(&format!("{}>\n    <failure message=\"{}\">{}</failure>\n  </testcase>\n", open, escape(first_line), escape(message)));
+            },
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.chars().filter(|&c| c == '\t' || c == '\n' || c >= ' ').fold(String::new(), |mut escaped, c| {
+        match c {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c    => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+/// The name of a build target from its identifier, e.g. `root/Test` from `file:/p/#root/Test`.
+fn target_name(target: &Value) -> String {
+    let uri = target["uri"].as_str().unwrap_or("tests");
+    uri.rsplit_once('#').map_or(uri, |(_, name)| name).to_owned()
+}
+
+/// E.g. `0.003s` or `0.01`.
+fn parse_seconds(s: &str) -> Option<Duration> {
+    s.trim_end_matches('s').parse::<f64>().ok().filter(|secs| *secs >= 0.0).map(Duration::from_secs_f64)
+}
+
+/// Drops the ANSI escape sequences test frameworks colour their output with.
+fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() { if c.is_ascii_alphabetic() { break } }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(lines: &[&str]) -> TestReport {
+        let mut report = TestReport::default();
+        for line in lines { report.on_log(line) }
+        report
+    }
+
+    fn case(name: &str, status: Status, millis: Option<u64>, message: Option<&str>) -> TestCase {
+        TestCase { name: name.to_owned(), status, duration: millis.map(Duration::from_millis), message: message.map(str::to_owned) }
+    }
+
+    #[test]
+    fn test_commands() {
+        for command_line in &["test", "testOnly foo.BarSpec", "~testQuick", "core/test", "core / Test / testOnly *Bar", "; compile; Test/test"] {
+            assert!(is_test_command(command_line), "{}", command_line);
+        }
+        for command_line in &["compile", "run", "~compile", "testClasses", "Test/compile", "show test", "show core / test"] {
+            assert!(!is_test_command(command_line), "{}", command_line);
+        }
+    }
+
+    #[test]
+    fn scalatest() {
+        let report = report(&[
+            "\x1b[32mBarSpec:\x1b[0m",
+            "\x1b[32m- adds (3 milliseconds)\x1b[0m",
+            "\x1b[31m- subtracts *** FAILED *** (5 milliseconds)\x1b[0m",
+            "\x1b[31m  1 did not equal 2 (BarSpec.scala:10)\x1b[0m",
+            "\x1b[33m- later !!! IGNORED !!!\x1b[0m",
+        ]);
+        assert_eq!(report.suites[0].cases, vec![
+            case("adds", Status::Passed, Some(3), None),
+            case("subtracts", Status::Failed, Some(5), Some("1 did not equal 2 (BarSpec.scala:10)")),
+            case("later", Status::Ignored, None, None),
+        ]);
+    }
+
+    #[test]
+    fn munit() {
+        let report = report(&[
+            "foo.BarSuite:",
+            "  + adds 0.003s",
+            "==> X foo.BarSuite.subtracts  0.01s munit.ComparisonFailException: values are not the same",
+            "==> i foo.BarSuite.later ignored",
+        ]);
+        assert_eq!(report.suites[0].name, "foo.BarSuite");
+        assert_eq!(report.suites[0].cases, vec![
+            case("adds", Status::Passed, Some(3), None),
+            case("subtracts", Status::Failed, Some(10), Some("munit.ComparisonFailException: values are not the same")),
+            case("later", Status::Ignored, None, None),
+        ]);
+    }
+
+    #[test]
+    fn junit_interface() {
+        let report = report(&[
+            "Test run foo.BarTest started",
+            "Test foo.BarTest.adds started",
+            "Test foo.BarTest.adds finished, took 0.001 sec",
+            "Test foo.BarTest.subtracts failed: java.lang.AssertionError: expected:<1> but was:<2>, took 0.002 sec",
+            "Test foo.BarTest.later ignored",
+            "Test run foo.BarTest finished: 1 failed, 1 ignored, 3 total, 0.05s",
+        ]);
+        assert_eq!(report.suites[0].name, "foo.BarTest");
+        assert_eq!(report.suites[0].cases, vec![
+            case("adds", Status::Passed, Some(1), None),
+            case("subtracts", Status::Failed, Some(2), Some("java.lang.AssertionError: expected:<1> but was:<2>")),
+            case("later", Status::Ignored, None, None),
+        ]);
+        assert_eq!(report.suites[0].duration(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn not_tests() {
+        assert!(report(&["Compiling 1 Scala source to /p/target", "- a list item", "Done:", "Passed: Total 3, Failed 0"]).is_empty());
+    }
+
+    #[test]
+    fn lists_after_the_suites() {
+        let report = report(&[
+            "BarSpec:",
+            "- adds (3 milliseconds)",
+            "Run completed in 120 milliseconds.",
+            "- foo",
+            "Test run foo.BarTest started",
+            "Test foo.BarTest.adds finished, took 0.001 sec",
+            "Test run foo.BarTest finished: 0 failed, 0 ignored, 1 total, 0.002s",
+            "- bar",
+        ]);
+        assert_eq!(report.suites.iter().map(|suite| suite.cases.len()).collect::<Vec<_>>(), vec![1, 1]);
+    }
+
+    #[test]
+    fn bsp() {
+        let mut report = TestReport::default();
+        report.on_bsp("build/taskStart", &json!({"taskId": {"id": "1"}, "dataKind": "test-task", "data": {"target": {"uri": "file:/p/#root/Test"}}}));
+        report.on_bsp("build/taskFinish", &json!({"taskId": {"id": "2", "parents": ["1"]}, "dataKind": "test-finish",
+            "data": {"displayName": "subtracts", "status": 2, "message": "1 did not equal 2"}}));
+        report.on_bsp("build/taskFinish", &json!({"taskId": {"id": "1"}, "dataKind": "test-report",
+            "data": {"target": {"uri": "file:/p/#root/Test"}, "passed": 2, "failed": 1, "ignored": 0, "time": 120}}));
+        report.on_bsp("build/taskFinish", &json!({"taskId": {"id": "3"}, "dataKind": "test-report",
+            "data": {"target": {"uri": "file:/p/#core/Test"}, "passed": 4, "failed": 0, "skipped": 1}}));
+
+        assert_eq!(report.suites[0].cases, vec![case("subtracts", Status::Failed, None, Some("1 did not equal 2"))]);
+        assert_eq!(report.suites[0].duration(), Some(Duration::from_millis(120)));
+        assert_eq!(report.suites[1].counts(), Counts { passed: 4, failed: 0, ignored: 1 });
+    }
+
+    #[test]
+    fn summary_table() {
+        let report = report(&["BarSpec:", "- adds (3 milliseconds)", "- subtracts *** FAILED *** (5 milliseconds)", "QuxSpec:", "- later !!! IGNORED !!!"]);
+        assert_eq!(report.summary(false), concat!(
+            "suite     passed   failed  ignored      time\n",
+            "BarSpec        1        1        0     0.01s\n",
+            "QuxSpec        0        0        1         -\n",
+            "total          1        1        1     0.01s\n",
+        ));
+    }
+
+    #[test]
+    fn junit_xml_reports() {
+        let report = report(&["BarSpec:", "- adds (3 milliseconds)", "- <subtracts> *** FAILED *** (5 milliseconds)", "  1 did not equal 2", "- later !!! IGNORED !!!"]);
+        assert_eq!(junit_xml(&report.suites[0]), concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<testsuite name=\"BarSpec\" tests=\"3\" failures=\"1\" errors=\"0\" skipped=\"1\" time=\"0.008\">\n",
+            "  <testcase classname=\"BarSpec\" name=\"adds\" time=\"0.003\"/>\n",
+            "  <testcase classname=\"BarSpec\" name=\"&lt;subtracts&gt;\" time=\"0.005\">\n",
+            "    <failure message=\"1 did not equal 2\">1 did not equal 2</failure>\n",
+            "  </testcase>\n",
+            "  <testcase classname=\"BarSpec\" name=\"later\" time=\"0.000\">\n",
+            "    <skipped/>\n",
+            "  </testcase>\n",
+            "</testsuite>\n",
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+//! `-timings` and `-timings-trace <path>`: where the time goes in a run of sbtl, in phases of the
+//! launcher (e.g. probing the java version) and the client (e.g. the round trip of `sbt/exec`),
+//! reported as a table and as Chrome's trace events, which `chrome://tracing` and Perfetto load.
+
+use std::cmp::Reverse;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant, };
+
+use serde_json::Value;
+
+use crate::launcher;
+
+lazy_static! {
+    /// When we started, which is when main touches it, see `start`.
+    static ref START: Instant = Instant::now();
+    static ref PHASES: Mutex<Vec<Phase>> = Mutex::new(Vec::new());
+    static ref TABLE: bool = env::args().take_while(|arg| arg != "--").any(|arg| arg == "-timings");
+    static ref TRACE: Option<PathBuf> = launcher::option_arg(env::args().skip(1), "-timings-trace").map(PathBuf::from);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Phase {
+    category: &'static str,
+        name: &'static str,
+       start: Duration,
+    duration: Duration,
+}
+
+/// Starts the clock.
+pub fn start() { lazy_static::initialize(&START) }
+
+/// Runs `f`, timing it as the given phase.
+pub fn time<T, F: FnOnce() -> T>(category: &'static str, name: &'static str, f: F) -> T {
+    let start = START.elapsed();
+    let result = f();
+    let duration = START.elapsed() - start;
+    PHASES.lock().unwrap().push(Phase { category, name, start, duration });
+    result
+}
+
+/// Reports the phases timed so far, if asked to, e.g. before exiting or exec'ing the JVM.
+pub fn report() {
+    let mut phases = PHASES.lock().unwrap().clone();
+    phases.sort_by_key(|phase| (phase.start, Reverse(phase.duration))); // phases before the ones within them
+    let total = START.elapsed();
+    if *TABLE {
+        eprint!("{}", table(&phases, total));
+    }
+    if let Some(path) = &*TRACE {
+        let trace = serde_json::to_string_pretty(&trace_events(&phases, process::id())).unwrap();
+        if let Err(e) = fs::write(path, trace) {
+            eprintln!("[error] failed to write the trace to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// E.g. `client/connect   0.001s   0.002s`, with when each phase started and how long it took.
+fn table(phases: &[Phase], total: Duration) -> String {
+    let names: Vec<String> = phases.iter().map(|phase| format!("{}/{}", phase.category, phase.name)).collect();
+    let width = names.iter().map(String::len).chain(Some("phase".len())).max().unwrap_or(0);
+    let secs = |d: Duration| format!("{:.3}s", d.as_secs_f64());
+    let mut table = format!("{:<width$}  {:>9}  {:>9}\n", "phase", "start", "took", width = width);
+    for (name, phase) in names.iter().zip(phases) {
+        table.push_str(&format!("{:<width$}  {:>9}  {:>9}\n", name, secs(phase.start), secs(phase.duration), width = width));
+    }
+    table.push_str(&format!("{:<width$}  {:>9}  {:>9}\n", "total", "", secs(total), width = width));
+    table
+}
+
+/// The phases as complete (`X`) events of Chrome's trace event format, timed in microseconds.
+fn trace_events(phases: &[Phase], pid: u32) -> Value {
+    let mut events = vec![json!({"name": "process_name", "ph": "M", "pid": pid, "tid": 0, "args": {"name": "sbtl"}})];
+    events.extend(phases.iter().map(|phase| json!({
+        "name": phase.name,
+        "cat": phase.category,
+        "ph": "X",
+        "ts": phase.start.as_micros() as u64,
+        "dur": phase.duration.as_micros() as u64,
+        "pid": pid,
+        "tid": 0,
+    })));
+    json!({"traceEvents": events, "displayTimeUnit": "ms"})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases() -> Vec<Phase> {
+        vec![
+            Phase { category: "client", name: "connect", start: Duration::from_millis(1), duration: Duration::from_micros(1500) },
+            Phase { category: "client", name: "sbt/exec", start: Duration::from_millis(3), duration: Duration::from_millis(1200) },
+        ]
+    }
+
+    #[test]
+    fn timings_table() {
+        assert_eq!(table(&phases(), Duration::from_millis(1210)), concat!(
+            "phase                start       took\n",
+            "client/connect      0.001s     0.002s\n",
+            "client/sbt/exec     0.003s     1.200s\n",
+            "total                          1.210s\n",
+        ));
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let trace = trace_events(&phases(), 42);
+        assert_eq!(trace["traceEvents"][0]["ph"], "M");
+        assert_eq!(trace["traceEvents"][1], json!({"name": "connect", "cat": "client", "ph": "X", "ts": 1000, "dur": 1500, "pid": 42, "tid": 0}));
+        assert_eq!(trace["traceEvents"][2]["ts"], 3000);
+    }
+
+    #[test]
+    fn times_phases() {
+        start();
+        assert_eq!(time("test", "phase", || 42), 42);
+        assert!(PHASES.lock().unwrap().iter().any(|phase| phase.name == "phase"));
+    }
+}
@@ -0,0 +1,71 @@
+//! Follows the iterations of a continuous command, e.g. `~compile`, run on the server, from its
+//! log messages: each build the watch triggers gets a separator and ends with whether it passed.
+
+use crate::log::Level;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether the command line is a continuous one.
+pub fn is_continuous(command_line: &str) -> bool {
+    command_line.trim_start().starts_with('~')
+}
+
+pub struct Watch {
+       colors: bool,
+    iteration: u32,
+       failed: bool,
+}
+
+impl Watch {
+    pub fn new(colors: bool) -> Watch {
+        Watch { colors, iteration: 1, failed: false }
+    }
+
+    /// What to print before the message: a separator when it says a new build's been triggered,
+    /// e.g. `Build triggered by /p/src/main/scala/Foo.scala. Running 'compile'.`
+    pub fn before(&mut self, msg: &str) -> Option<String> {
+        if !msg.starts_with("Build triggered by") { return None }
+        self.iteration += 1;
+        self.failed = false;
+        Some(format!("-------- iteration {} --------", self.iteration))
+    }
+
+    /// What to print after the message: whether the build passed, when it's finished, which sbt
+    /// marks with `Total time: ...`.
+    pub fn after(&mut self, level: Level, msg: &str) -> Option<String> {
+        if level == Level::Error { self.failed = true }
+        if !msg.starts_with("Total time:") { return None }
+        let (color, result) = if self.failed { (RED, "failed") } else { (GREEN, "passed") };
+        let result = if self.colors { format!("{}{}{}", color, result, RESET) } else { result.to_owned() };
+        Some(format!("iteration {} {}, waiting for changes (press Enter to stop)", self.iteration, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuous_commands() {
+        assert!(is_continuous("~compile"));
+        assert!(is_continuous(" ~ testQuick"));
+        assert!(!is_continuous("compile"));
+    }
+
+    #[test]
+    fn iterations() {
+        let mut watch = Watch::new(false);
+        assert_eq!(watch.before("compiling 1 Scala source"), None);
+        assert_eq!(watch.after(Level::Info, "Total time: 1 s"), Some("iteration 1 passed, waiting for changes (press Enter to stop)".to_owned()));
+        assert_eq!(watch.after(Level::Info, "1. Monitoring source files for root/compile..."), None);
+
+        assert_eq!(watch.before("Build triggered by /p/Foo.scala. Running 'compile'."), Some("-------- iteration 2 --------".to_owned()));
+        assert_eq!(watch.after(Level::Error, "type mismatch"), None);
+        assert_eq!(watch.after(Level::Error, "Total time: 0 s"), Some("iteration 2 failed, waiting for changes (press Enter to stop)".to_owned()));
+
+        watch.before("Build triggered by /p/Foo.scala. Running 'compile'.");
+        assert_eq!(watch.after(Level::Info, "Total time: 0 s"), Some("iteration 3 passed, waiting for changes (press Enter to stop)".to_owned()));
+    }
+}